@@ -1,40 +1,93 @@
 /// Much of this example borrows from the `tui-rs` examples, and was modified for our purposes.
 /// See: https://github.com/fdehau/tui-rs/blob/master/examples/user_input.rs
-use chrono::prelude::*;
-const TIME_FORMAT: &'static str = "%H:%M:%S";
+use std::env;
 
 use syncterm;
+use syncterm::keymap::{Keymap, Resolved, GLOBAL};
+use syncterm::stats::NetStats;
+use syncterm::widgets::{self, InputBox, MessageLog, Roster, StatusBar};
 
 use tui::Terminal;
 use tui::backend::MouseBackend;
-use tui::layout::{Direction, Group, Rect, Size};
-use tui::style::{Color, Style};
-use tui::widgets::{Block, Borders, Paragraph, Widget};
+use tui::layout::{self, Direction, Rect, Size};
 
 use messages::*;
 
+#[derive(Clone)]
+enum Action {
+    Exit,
+    ToggleMode,
+    Clear,
+}
+
 pub struct App {
     user_name: String,
     size: Rect,
-    input: String,
+    keymap: Keymap<Action>,
+    input: InputBox,
     input_mode: Mode,
-    messages: Vec<(DateTime<Local>, String, String)>,
-    commands: Vec<(DateTime<Local>, String, String, String)>,
-    terminal: Terminal<MouseBackend>,
+    messages: MessageLog,
+    commands: MessageLog,
+    roster: Roster,
+    status: StatusBar,
+    // Only set up once drawing starts, since line mode never draws
+    terminal: Option<Terminal<MouseBackend>>,
+}
+
+fn new_terminal() -> Terminal<MouseBackend> {
+    Terminal::new(MouseBackend::new().unwrap()).unwrap()
 }
 
 impl App {
     pub fn new(user_name: String) -> App {
+        let mut keymap = Keymap::new()
+            .action("exit", Action::Exit)
+            .action("toggle-mode", Action::ToggleMode)
+            .action("clear", Action::Clear)
+            .bind(GLOBAL, "ctrl-c", "exit")
+            .bind(GLOBAL, "esc", "exit")
+            .bind(GLOBAL, "ctrl-t", "toggle-mode")
+            .bind(GLOBAL, "ctrl-l", "clear");
+        keymap.set_mode("chat");
+        // Users can override the bindings above with their own TOML file
+        if let Ok(path) = env::var("GIT_HELPER_KEYS") {
+            if let Err(e) = keymap.load_file(&path) {
+                eprintln!("Ignoring key bindings: {}", e);
+            }
+        }
+
         App {
             user_name,
             size: Rect::default(),
-            input: String::new(),
+            keymap,
+            input: InputBox::new("Chat"),
             input_mode: Mode::Chat,
-            messages: Vec::new(),
-            commands: Vec::new(),
-            terminal: Terminal::new(MouseBackend::new().unwrap()).unwrap(),
+            messages: MessageLog::new("Messages"),
+            commands: MessageLog::new("Commands"),
+            roster: Roster::new("Users"),
+            status: StatusBar::new(),
+            terminal: None,
         }
     }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.keymap.set_mode(match mode {
+            Mode::Chat => "chat",
+            Mode::Cmd => "cmd",
+        });
+        self.input.set_title(match mode {
+            Mode::Chat => "Chat",
+            Mode::Cmd => "Command",
+        });
+        self.input_mode = mode;
+    }
+
+    fn clear(&mut self) {
+        match self.input_mode {
+            Mode::Cmd => self.commands.clear(),
+            Mode::Chat => self.messages.clear(),
+        };
+    }
 }
 
 impl syncterm::client::ShellClient<Message, Response> for App {
@@ -43,145 +96,141 @@ impl syncterm::client::ShellClient<Message, Response> for App {
     }
 
     fn on_key(&mut self, key: syncterm::client::Key) -> syncterm::client::KeyAction<Message> {
-        match key {
-            syncterm::client::Key::Ctrl('c') | syncterm::client::Key::Esc => {
-                return syncterm::client::KeyAction::Exit;
+        let keys = match self.keymap.resolve(key) {
+            Resolved::Action(Action::Exit) => return syncterm::client::KeyAction::Exit,
+            Resolved::Action(Action::ToggleMode) => {
+                let mode = match self.input_mode {
+                    Mode::Chat => Mode::Cmd,
+                    Mode::Cmd => Mode::Chat,
+                };
+                self.set_mode(mode);
+                return syncterm::client::KeyAction::DoNothing;
             }
-            syncterm::client::Key::Char('\n') => {
-                let message = self.input.drain(..).collect::<String>();
-                match message.as_ref() {
-                    "CHAT" => {
-                        self.input_mode = Mode::Chat;
-                    }
-                    "CMD" => {
-                        self.input_mode = Mode::Cmd;
-                    }
-                    "CLEAR" => {
-                        match self.input_mode {
-                            Mode::Cmd => self.commands.clear(),
-                            Mode::Chat => self.messages.clear(),
-                        };
-                    }
-                    _ => {
-                        return syncterm::client::KeyAction::SendMessage(Message {
-                            content: message,
-                            mode: self.input_mode.clone(),
-                            user_name: self.user_name.clone(),
-                        });
-                    }
-                }
+            Resolved::Action(Action::Clear) => {
+                self.clear();
+                return syncterm::client::KeyAction::DoNothing;
             }
-            syncterm::client::Key::Char(c) => {
-                self.input.push(c);
+            Resolved::Pending => return syncterm::client::KeyAction::DoNothing,
+            Resolved::Unbound(keys) => keys,
+        };
+
+        let mut submitted = None;
+        for key in keys {
+            if let Some(message) = self.input.handle_key(key) {
+                submitted = Some(message);
             }
-            syncterm::client::Key::Backspace => {
-                self.input.pop();
+        }
+        let message = match submitted {
+            Some(message) => message,
+            None => return syncterm::client::KeyAction::DoNothing,
+        };
+
+        match message.as_ref() {
+            "CHAT" => self.set_mode(Mode::Chat),
+            "CMD" => self.set_mode(Mode::Cmd),
+            "CLEAR" => self.clear(),
+            _ => {
+                return syncterm::client::KeyAction::SendMessage(Message {
+                    content: message,
+                    mode: self.input_mode.clone(),
+                    user_name: self.user_name.clone(),
+                });
             }
-            _ => {}
         }
 
         syncterm::client::KeyAction::DoNothing
     }
 
     fn receive_response(&mut self, response: Response) {
+        let user_name = &response.og_msg.user_name;
+        self.roster.insert(user_name);
         match response.og_msg.mode {
-            Mode::Chat => {
-                self.messages
-                    .push((Local::now(), response.og_msg.user_name, response.response));
-            }
-            Mode::Cmd => {
-                self.commands.push((
-                    Local::now(),
-                    response.og_msg.user_name,
+            Mode::Chat => self.messages.push(user_name, &response.response),
+            Mode::Cmd => self.commands.push(
+                user_name,
+                &format!(
+                    ">> {}\n{}\n",
                     response.og_msg.content,
-                    response.response,
-                ));
-            }
+                    response.response.trim_end()
+                ),
+            ),
         };
     }
 
+    fn on_connection(&mut self, connected: bool) {
+        self.status.set_connected(connected);
+    }
+
+    fn on_stats(&mut self, stats: &NetStats) {
+        if let Some(rtt) = stats.rtt {
+            self.status.set_latency(rtt);
+        }
+    }
+
+    fn response_line(&self, response: &Response) -> Option<String> {
+        Some(match response.og_msg.mode {
+            Mode::Chat => format!("{}: {}", response.og_msg.user_name, response.response),
+            Mode::Cmd => format!(
+                "{} >> {}\n{}",
+                response.og_msg.user_name,
+                response.og_msg.content,
+                response.response.trim_end()
+            ),
+        })
+    }
+
+    fn terminal_options(&self) -> syncterm::client::TerminalOptions {
+        syncterm::client::TerminalOptions::managed()
+    }
+
+    fn max_redraw_rate(&self) -> Option<u32> {
+        Some(30)
+    }
+
+    fn debug_overlay_key(&self) -> Option<syncterm::client::Key> {
+        Some(syncterm::client::Key::F(12))
+    }
+
+    fn on_invalidate(&mut self) {
+        let size = self.size;
+        if let Some(ref mut terminal) = self.terminal {
+            terminal.resize(size).unwrap();
+        }
+    }
+
+    fn on_resize(&mut self, cols: u16, rows: u16) {
+        self.size = Rect::new(0, 0, cols, rows);
+        self.terminal
+            .get_or_insert_with(new_terminal)
+            .resize(self.size)
+            .unwrap();
+    }
+
     fn first_draw(&mut self) {
-        self.terminal.clear().unwrap();
-        self.terminal.hide_cursor().unwrap();
-        self.size = self.terminal.size().unwrap();
+        self.terminal.get_or_insert_with(new_terminal).clear().unwrap();
 
         self.draw();
     }
 
-    fn last_draw(&mut self) {
-        self.terminal.show_cursor().unwrap();
-    }
+    fn last_draw(&mut self) {}
 
     fn draw(&mut self) {
-        let mut size = self.terminal.size().unwrap();
-        self.terminal.resize(size).unwrap();
-
-        size = self.terminal.size().unwrap();
-        let mode = &self.input_mode;
-        let input = &self.input;
-        let messages = &self.messages;
-        let commands = &self.commands;
-
-        Group::default()
-            .direction(Direction::Vertical)
-            .margin(2)
-            .sizes(&[Size::Fixed(3), Size::Min(1)])
-            .render(&mut self.terminal, &size, |t, chunks| {
-                Paragraph::default()
-                    .style(Style::default().fg(Color::Yellow))
-                    .block(Block::default().borders(Borders::ALL).title(match *mode {
-                        Mode::Chat => "Chat",
-                        Mode::Cmd => "Command",
-                    }))
-                    .text(input)
-                    .render(t, &chunks[0]);
-
-                Group::default()
-                    .direction(Direction::Horizontal)
-                    .margin(0)
-                    .sizes(&[Size::Percent(50), Size::Percent(50)])
-                    .render(t, &chunks[1], |t, chunks| {
-                        // Use Paragraphs so we can get text wrapping
-                        let messages: String = messages.iter().rev().fold(
-                            "".to_owned(),
-                            |mut acc, (t, u, m)| {
-                                acc.push_str(&format!(
-                                    "{}: {}: {}\n",
-                                    t.format(TIME_FORMAT).to_string(),
-                                    u,
-                                    m
-                                ));
-                                acc
-                            },
-                        );
-                        Paragraph::default()
-                            .block(Block::default().borders(Borders::ALL).title("Messages"))
-                            .wrap(true)
-                            .text(&messages)
-                            .render(t, &chunks[0]);
-
-                        let commands: String = commands.iter().rev().fold(
-                            "".to_owned(),
-                            |mut acc, (t, u, c, m)| {
-                                acc.push_str(&format!(
-                                    "{}: {} >> {}\n{}{}\n",
-                                    t.format(TIME_FORMAT).to_string(),
-                                    u,
-                                    c,
-                                    m,
-                                    if m.ends_with("\n") { "" } else { "\n" }
-                                ));
-                                acc
-                            },
-                        );
-                        Paragraph::default()
-                            .block(Block::default().borders(Borders::ALL).title("Commands"))
-                            .wrap(true)
-                            .text(&commands)
-                            .render(t, &chunks[1]);
-                    });
-            });
-
-        self.terminal.draw().unwrap();
+        let size = self.size;
+        let terminal = self.terminal.get_or_insert_with(new_terminal);
+
+        let layout = widgets::chat_layout(&size);
+        let panes = layout::split(
+            &layout.log,
+            &Direction::Horizontal,
+            0,
+            &[Size::Percent(50), Size::Percent(50)],
+        );
+        terminal.render(&mut self.input, &layout.input);
+        terminal.render(&mut self.messages, &panes[0]);
+        terminal.render(&mut self.commands, &panes[1]);
+        terminal.render(&mut self.roster, &layout.roster);
+        terminal.render(&mut self.status, &layout.status);
+
+        terminal.draw().unwrap();
     }
 }
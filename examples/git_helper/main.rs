@@ -1,4 +1,3 @@
-extern crate chrono;
 #[macro_use]
 extern crate serde_derive;
 extern crate syncterm;
@@ -16,6 +15,7 @@ fn main() {
     if let Some(name) = args.next() {
         syncterm::client::connect(client::App::new(name)).unwrap();
     } else {
-        syncterm::server::spawn_shell_and_listen(server::App()).unwrap();
+        // Runs the server's worker pool, which spawn_shell_and_listen doesn't
+        syncterm::server::spawn_shell(server::App()).unwrap().join();
     }
 }
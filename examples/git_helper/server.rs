@@ -11,6 +11,11 @@ impl syncterm::server::ShellServer<Message, Response> for App {
         "127.0.0.1:8080".to_owned()
     }
 
+    fn worker_pool(&self) -> Option<syncterm::server::WorkerPool> {
+        // Keep chat flowing while someone runs a slow command
+        Some(syncterm::server::WorkerPool::new(4))
+    }
+
     fn process_input(&self, input: Message) -> Response {
         let response = match input.mode {
             Mode::Chat => {
@@ -44,7 +49,7 @@ fn run_command(content: &str) -> Result<String, String> {
     let mut words = content.split_whitespace();
     if let Some(cmd) = words.next() {
         let mut process = Command::new(cmd);
-        while let Some(arg) = words.next() {
+        for arg in words {
             process.arg(arg);
         }
 
@@ -54,7 +59,7 @@ fn run_command(content: &str) -> Result<String, String> {
                 let stderr = ::std::str::from_utf8(&output.stderr).expect("Non-utf8 stderr");
 
                 println!("MAIN: shell returned stdout {:?}, relaying...", stdout);
-                if stderr != "" {
+                if !stderr.is_empty() {
                     println!(
                         "MAIN: shell returned stderr {:?}, thought you should know...",
                         stderr
@@ -68,6 +68,10 @@ impl syncterm::client::ShellClient<Message, Response> for App {
             .push((Local::now(), response.og_msg.user_name, response.response));
     }
 
+    fn response_line(&self, response: &Response) -> Option<String> {
+        Some(format!("{} >> {}", response.og_msg.user_name, response.response))
+    }
+
     fn first_draw(&mut self) {
         println!("Welcome! Type UPPER for uppercase mode and LOWER for lowercase mode");
     }
@@ -78,7 +82,7 @@ impl syncterm::client::ShellClient<Message, Response> for App {
 
     fn draw(&mut self) {
         if let Some(m) = self.messages.pop() {
-            println!("{}: {} >> {}", m.0.format("%H:%M:%S").to_string(), m.1, m.2);
+            println!("{}: {} >> {}", m.0.format("%H:%M:%S"), m.1, m.2);
         }
     }
 }
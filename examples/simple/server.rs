@@ -1,6 +1,10 @@
 use messages::*;
 
+use std::sync::Arc;
+
 use syncterm;
+use syncterm::observer::ServerEvent;
+use syncterm::server::ServerOptions;
 
 pub struct App();
 
@@ -28,4 +32,11 @@ impl syncterm::server::ShellServer<Message, Response> for App {
             response,
         }
     }
+
+    fn options(&self) -> ServerOptions {
+        ServerOptions::new().observer(Arc::new(|event: &ServerEvent| match *event {
+            ServerEvent::InputReceived { .. } | ServerEvent::Broadcast { .. } => {}
+            _ => println!("MAIN: {:?}", event),
+        }))
+    }
 }
@@ -0,0 +1,544 @@
+//! Lets a server's operator manage it while it runs, over a Unix socket.
+//!
+//! Each line sent to the socket is a command, answered with any output followed by `ok` or
+//! `error: <why>`:
+//!
+//! ```text
+//! list                  lists connected clients: id, address, identity and whether muted
+//! kick <id>             closes a client's connection
+//! ban <id>|<ip>         kicks every client from an address, and refuses it from now on
+//! unban <ip>            accepts connections from an address again
+//! mute <id>             ignores a client's inputs, still acknowledging them, and refuses its
+//!                       requests
+//! unmute <id>           accepts a client's inputs again
+//! broadcast <text>      sends the server's announcement of the text to every client
+//! shutdown              stops accepting connections and inputs, then closes every connection
+//!                       once the inputs already received have been answered
+//! ```
+//!
+//! Every kind of server takes admin commands, set up with `ServerOptions::admin`. Only a
+//! `ShellServer` makes announcements, with `ShellServer::announce`, so lockstep and synced
+//! servers refuse `broadcast`; likewise, only a `ShellServer` lists who its clients are, with
+//! `ShellServer::identify`.
+//!
+//! With `socat`, for example: `socat - UNIX-CONNECT:/tmp/chat-admin.sock`.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use observer::{ServerEvent, ServerObserver};
+use server::ClientId;
+
+/// Passed to `ServerOptions::admin` to accept admin commands on a Unix socket at `path`.
+///
+/// The socket is only accessible to the user running the server. Any socket already at `path`,
+/// such as one left behind by a previous run, is replaced; the server fails to start if anything
+/// else is there.
+///
+/// # Examples
+/// ```
+/// # use syncterm::admin::Admin;
+/// # use syncterm::server::ServerOptions;
+/// let options = ServerOptions::new().admin(Admin::new("/tmp/chat-admin.sock"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Admin {
+    path: PathBuf,
+}
+
+impl Admin {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+/// Broadcasts an operator's announcement to every client, returning how many it reached, or
+/// `None` if the server has no announcements.
+pub(crate) type Announce = Arc<dyn Fn(&str) -> Option<usize> + Send + Sync>;
+
+/// The state of a server's connections that an operator can change: who's connected, banned and
+/// muted, and whether the server is shutting down.
+#[derive(Default)]
+pub(crate) struct Control {
+    inner: Mutex<ControlState>,
+    // Notified whenever a client's connection closes
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct ControlState {
+    clients: BTreeMap<ClientId, ClientInfo>,
+    banned: HashSet<IpAddr>,
+    muted: HashSet<ClientId>,
+    shutting_down: bool,
+    announce: Option<Announce>,
+    // Where the server accepts connections, connected to in order to wake it up on shutdown
+    local_addr: Option<SocketAddr>,
+    socket: Option<PathBuf>,
+}
+
+struct ClientInfo {
+    addr: SocketAddr,
+    stream: TcpStream,
+    identity: Option<String>,
+}
+
+impl Control {
+    fn lock(&self) -> MutexGuard<'_, ControlState> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_local_addr(&self, addr: SocketAddr) {
+        self.lock().local_addr = Some(addr);
+    }
+
+    /// Keeps track of a new client's connection. Returns false, having closed it, if the client's
+    /// address is banned or the server is shutting down.
+    pub fn register(&self, client: ClientId, addr: SocketAddr, stream: &TcpStream) -> bool {
+        let mut state = self.lock();
+        if state.shutting_down || state.banned.contains(&addr.ip()) {
+            let _ = stream.shutdown(Shutdown::Both);
+            return false;
+        }
+
+        if let Ok(stream) = stream.try_clone() {
+            state.clients.insert(
+                client,
+                ClientInfo {
+                    addr,
+                    stream,
+                    identity: None,
+                },
+            );
+        }
+        true
+    }
+
+    pub fn unregister(&self, client: ClientId) {
+        let mut state = self.lock();
+        state.clients.remove(&client);
+        state.muted.remove(&client);
+        self.closed.notify_all();
+    }
+
+    /// Sets how to make the server's announcements, which it has none of until then.
+    pub fn set_announce(&self, announce: Announce) {
+        self.lock().announce = Some(announce);
+    }
+
+    /// Broadcasts an operator's announcement, returning how many clients it reached.
+    fn announce(&self, text: &str) -> Result<usize, String> {
+        // Not called under the lock, since announcing waits on the server
+        let announce = self.lock().announce.clone();
+        announce
+            .and_then(|announce| announce(text))
+            .ok_or_else(|| "This server has no announcements".to_owned())
+    }
+
+    /// Records who a client says it is, as returned by `ShellServer::identify`.
+    pub fn identify(&self, client: ClientId, identity: String) {
+        if let Some(info) = self.lock().clients.get_mut(&client) {
+            info.identity = Some(identity);
+        }
+    }
+
+    pub fn is_muted(&self, client: ClientId) -> bool {
+        self.lock().muted.contains(&client)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.lock().shutting_down
+    }
+
+    /// Stops accepting new connections, and reading from the ones already open. The server closes
+    /// them once it's answered every input already read, through `wait_for_clients`.
+    pub fn shutdown(&self) {
+        let mut state = self.lock();
+        state.shutting_down = true;
+        state.announce = None;
+        for info in state.clients.values() {
+            let _ = info.stream.shutdown(Shutdown::Read);
+        }
+        if let Some(ref socket) = state.socket {
+            // Wakes the admin listener too, so that it stops
+            let _ = UnixStream::connect(socket);
+            let _ = fs::remove_file(socket);
+        }
+
+        // The listener only checks for a shutdown when a connection arrives
+        if let Some(addr) = state.local_addr {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    /// Waits up to `timeout` for every client's connection to close, then closes any that haven't.
+    pub fn wait_for_clients(&self, timeout: Duration) {
+        let state = self.lock();
+        let (state, _) = self
+            .closed
+            .wait_timeout_while(state, timeout, |state| !state.clients.is_empty())
+            .unwrap_or_else(|e| e.into_inner());
+        for info in state.clients.values() {
+            let _ = info.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn list(&self) -> String {
+        let state = self.lock();
+        let mut out = String::new();
+        for (client, info) in &state.clients {
+            out.push_str(&format!(
+                "{} {} {}{}\n",
+                client.0,
+                info.addr,
+                info.identity.as_ref().map_or("-", |i| i.as_str()),
+                if state.muted.contains(client) {
+                    " muted"
+                } else {
+                    ""
+                }
+            ));
+        }
+        out
+    }
+
+    fn kick(&self, client: ClientId) -> Result<(), String> {
+        match self.lock().clients.get(&client) {
+            Some(info) => {
+                let _ = info.stream.shutdown(Shutdown::Both);
+                Ok(())
+            }
+            None => Err(format!("No client {}", client.0)),
+        }
+    }
+
+    /// Bans an address, kicking every client connected from it. Returns how many were kicked.
+    fn ban(&self, ip: IpAddr) -> usize {
+        let mut state = self.lock();
+        state.banned.insert(ip);
+
+        let mut kicked = 0;
+        for info in state.clients.values().filter(|info| info.addr.ip() == ip) {
+            let _ = info.stream.shutdown(Shutdown::Both);
+            kicked += 1;
+        }
+        kicked
+    }
+
+    fn set_muted(&self, client: ClientId, muted: bool) -> Result<(), String> {
+        let mut state = self.lock();
+        if !state.clients.contains_key(&client) {
+            return Err(format!("No client {}", client.0));
+        }
+
+        if muted {
+            state.muted.insert(client);
+        } else {
+            state.muted.remove(&client);
+        }
+        Ok(())
+    }
+
+    fn address_of(&self, client: ClientId) -> Option<IpAddr> {
+        self.lock().clients.get(&client).map(|info| info.addr.ip())
+    }
+}
+
+/// Starts accepting admin commands on the socket in `admin`, in the background.
+///
+/// Errors if the socket can't be bound.
+pub(crate) fn serve(
+    admin: Admin,
+    control: Arc<Control>,
+    observer: Arc<dyn ServerObserver>,
+) -> Result<(), String> {
+    let path = admin.path;
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(format!(
+                "Failed to bind admin socket {:?}: something other than a socket is there",
+                path
+            ))
+        }
+        _ => {}
+    }
+    let listener = bind_private(&path)
+        .map_err(|e| format!("Failed to bind admin socket {:?}: {:?}", path, e))?;
+    control.lock().socket = Some(path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if control.is_shutting_down() {
+                break;
+            }
+            let control = control.clone();
+            let observer = observer.clone();
+            thread::spawn(move || handle_operator(stream, &control, &*observer));
+        }
+    });
+
+    Ok(())
+}
+
+/// Binds a socket at `path` that only the current user can connect to, replacing any already there.
+///
+/// The socket is bound in a directory only the current user can enter, and restricted before it's
+/// moved into place, so that it's never open to anyone else.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let staged = dir.join("admin.sock");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+fn handle_operator(stream: UnixStream, control: &Control, observer: &dyn ServerObserver) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        observer.observe(&ServerEvent::AdminCommand {
+            command: command.to_owned(),
+        });
+        let reply = match run(command, control) {
+            Ok(output) => format!("{}ok\n", output),
+            Err(e) => format!("error: {}\n", e),
+        };
+        if writer.write_all(reply.as_bytes()).is_err() || control.is_shutting_down() {
+            return;
+        }
+    }
+}
+
+/// Carries out one admin command, returning its output.
+fn run(command: &str, control: &Control) -> Result<String, String> {
+    let (name, arg) = match command.find(char::is_whitespace) {
+        Some(i) => (&command[..i], command[i..].trim()),
+        None => (command, ""),
+    };
+    let client = || {
+        arg.parse()
+            .map(ClientId)
+            .map_err(|_| format!("Not a client id: {:?}", arg))
+    };
+
+    match name {
+        "list" => Ok(control.list()),
+        "kick" => control.kick(client()?).map(|()| String::new()),
+        "ban" => {
+            let ip = match arg.parse() {
+                Ok(ip) => ip,
+                Err(_) => control
+                    .address_of(client()?)
+                    .ok_or_else(|| format!("No client {}", arg))?,
+            };
+            Ok(format!("banned {}, kicked {}\n", ip, control.ban(ip)))
+        }
+        "unban" => {
+            let ip: IpAddr = arg
+                .parse()
+                .map_err(|_| format!("Not an address: {:?}", arg))?;
+            control.lock().banned.remove(&ip);
+            Ok(String::new())
+        }
+        "mute" => control.set_muted(client()?, true).map(|()| String::new()),
+        "unmute" => control.set_muted(client()?, false).map(|()| String::new()),
+        "broadcast" => control
+            .announce(arg)
+            .map(|clients| format!("sent to {} clients\n", clients)),
+        "shutdown" => {
+            control.shutdown();
+            Ok(String::new())
+        }
+        _ => Err(format!("Unknown command {:?}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::TcpListener;
+
+    /// Connects over loopback, returning both ends of the connection: the server's, then the
+    /// client's.
+    fn accept() -> (TcpStream, TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client_end = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        (stream, client_end, addr)
+    }
+
+    fn connect(control: &Control, client: u64) -> (TcpStream, TcpStream) {
+        let (stream, client_end, addr) = accept();
+        assert!(control.register(ClientId(client), addr, &stream));
+        (stream, client_end)
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_clients() {
+        let control = Control::default();
+
+        assert_eq!(
+            run("restart now", &control),
+            Err("Unknown command \"restart\"".to_owned())
+        );
+        assert_eq!(
+            run("kick", &control),
+            Err("Not a client id: \"\"".to_owned())
+        );
+        assert_eq!(run("mute 7", &control), Err("No client 7".to_owned()));
+        assert_eq!(
+            run("unban 10.0.0", &control),
+            Err("Not an address: \"10.0.0\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn lists_and_mutes_clients() {
+        let control = Control::default();
+        let _first = connect(&control, 1);
+        let _second = connect(&control, 2);
+        control.identify(ClientId(2), "ada".to_owned());
+
+        assert_eq!(run("mute  2", &control), Ok(String::new()));
+        assert!(control.is_muted(ClientId(2)));
+        let list = run("list", &control).unwrap();
+        let lines: Vec<_> = list.lines().collect();
+        assert!(lines[0].starts_with("1 127.0.0.1:") && lines[0].ends_with(" -"));
+        assert!(lines[1].ends_with(" ada muted"), "{:?}", lines[1]);
+
+        run("unmute 2", &control).unwrap();
+        assert!(!control.is_muted(ClientId(2)));
+        control.unregister(ClientId(1));
+        assert_eq!(run("list", &control).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn bans_a_clients_address() {
+        let control = Control::default();
+        let (stream, _client_end) = connect(&control, 1);
+
+        assert_eq!(
+            run("ban 1", &control),
+            Ok("banned 127.0.0.1, kicked 1\n".to_owned())
+        );
+        // Kicking shut the connection down
+        assert!(BufReader::new(&stream).lines().next().is_none());
+
+        let (stream, _client_end, addr) = accept();
+        assert!(!control.register(ClientId(2), addr, &stream));
+
+        run("unban 127.0.0.1", &control).unwrap();
+        assert!(control.register(ClientId(2), addr, &stream));
+    }
+
+    #[test]
+    fn broadcasts_announcements_if_the_server_makes_them() {
+        let control = Control::default();
+        assert_eq!(
+            run("broadcast hi", &control),
+            Err("This server has no announcements".to_owned())
+        );
+
+        control.set_announce(Arc::new(
+            |text: &str| {
+                if text == "hello all" {
+                    Some(3)
+                } else {
+                    None
+                }
+            },
+        ));
+        assert_eq!(
+            run("broadcast hello all", &control),
+            Ok("sent to 3 clients\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn shuts_down() {
+        let control = Control::default();
+        let (stream, _client_end) = connect(&control, 1);
+        control.set_announce(Arc::new(|_: &str| Some(1)));
+
+        assert_eq!(run("shutdown", &control), Ok(String::new()));
+        assert!(control.is_shutting_down());
+        // Stops reading, and making announcements, but leaves writing to the server
+        assert!(BufReader::new(&stream).lines().next().is_none());
+        assert!(run("broadcast hi", &control).is_err());
+        assert!((&stream).write_all(b"still writable\n").is_ok());
+
+        let (late, _late_client_end, addr) = accept();
+        assert!(!control.register(ClientId(2), addr, &late));
+
+        // Closes what's still open once it's done waiting
+        control.wait_for_clients(Duration::from_millis(10));
+        assert!((&stream).write_all(b"closed\n").is_err());
+    }
+
+    #[test]
+    fn serves_on_a_socket_only_its_user_can_reach() {
+        let dir = env::temp_dir().join(format!("syncterm-admin-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        let observer: Arc<dyn ServerObserver> = Arc::new(|_: &ServerEvent| {});
+        let serve_at = |path: &Path| {
+            let control = Arc::new(Control::default());
+            serve(Admin::new(path), control.clone(), observer.clone()).map(|()| control)
+        };
+
+        fs::write(&path, "not a socket").unwrap();
+        assert!(serve_at(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+
+        // Replaces a socket left behind by a previous run
+        fs::remove_file(&path).unwrap();
+        let _stale = UnixListener::bind(&path).unwrap();
+        let control = serve_at(&path).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        let mut operator = UnixStream::connect(&path).unwrap();
+        operator.write_all(b"list\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(&operator).read_line(&mut reply).unwrap();
+
+        control.shutdown();
+        let left = fs::read_dir(&dir).unwrap().count();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(reply, "ok\n");
+        // Nothing's left behind, not even the directory the socket was bound in
+        assert_eq!(left, 0);
+    }
+}
@@ -1,24 +1,115 @@
-use std::io;
+use std::env;
+use std::io::{self, BufRead};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use chan;
-pub use termion::event::Key;
+use signal_hook::consts::SIGWINCH;
+use signal_hook::iterator::Signals;
+use termion;
+pub use termion::event::{Event, Key, MouseButton, MouseEvent};
 use termion::input::TermRead;
 
+use interpolation::Interpolation;
+use lockstep::TurnInfo;
+use logging::{self, LogOptions};
+use outbox::{Delivery, MessageId, Outbox};
+pub use protocol::RequestId;
 use serde::{Serialize, de::DeserializeOwned};
+use session::{self, Frontend, Output, Single, UserInput};
 use shell_connection::ShellConnection;
+use stats::NetStats;
+pub use terminal::TerminalOptions;
+use terminal::TerminalSession;
 
-/// Returned by `ShellClient::on_key` to specify an API action to be triggered after a key is pressed.
+/// Returned by `ShellClient::on_key` (and `on_mouse`/`on_event`) to specify an API action to be
+/// triggered after a key is pressed.
 ///
 /// M must implement `serde::Serialize` to allow robust client-server communication.
 /// We recommend using the `serde_derive` crate and its `#[derive(Serialize)]` macro to achieve this.
+///
+/// The client UI is redrawn after every action except `SkipRedraw` and the exits.
 #[derive(Debug, Clone)]
 pub enum KeyAction<M: Serialize> {
     DoNothing,
+    /// Redraws the client UI without doing anything else
+    Redraw,
+    /// Does nothing, not even redrawing the client UI
+    SkipRedraw,
     /// Exits from synced terminal
     Exit,
+    /// Sends a final message to the server, then exits from synced terminal
+    ExitWithMessage(M),
     /// Sends a user's input to the server defined by ShellServer
     SendMessage(M),
+    /// Sends a user's input for the numbered turn of a lockstep server, which holds on to it
+    /// until that turn starts. Other inputs are sent for the turn that started last.
+    SendForTurn(u64, M),
+    /// Sends several inputs to the server, in order
+    SendMany(Vec<M>),
+    /// Sends an input to the server once the given time has passed
+    SendAfter(Duration, M),
+    /// Sends a user's input to the server, whose response is sent back to this client only and
+    /// delivered to `ShellClient::receive_reply`
+    Request(Request<M>),
+    /// Cancels a pending request: its reply or timeout will not be delivered
+    Cancel(RequestId),
+}
+
+/// Set to run clients in line mode even in a terminal, such as for use with a screen reader.
+const LINE_MODE_VAR: &str = "SYNCTERM_LINE_MODE";
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A message sent to the server with [KeyAction::Request](enum.KeyAction.html), correlated with
+/// its response by a [RequestId](struct.RequestId.html).
+#[derive(Debug, Clone)]
+pub struct Request<M: Serialize> {
+    pub(crate) id: RequestId,
+    pub(crate) message: M,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl<M: Serialize> Request<M> {
+    /// Creates a request with a fresh id and no timeout.
+    pub fn new(message: M) -> Self {
+        Self {
+            id: RequestId(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)),
+            message,
+            timeout: None,
+        }
+    }
+
+    /// Gives up on the request if no reply has arrived after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The id that the request's reply will be delivered with.
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+}
+
+impl<M: Serialize> From<M> for Request<M> {
+    fn from(message: M) -> Self {
+        Request::new(message)
+    }
+}
+
+/// Delivered to `ShellClient::receive_reply` in place of a reply that never arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// No reply arrived within the request's timeout.
+    TimedOut,
+    /// The connection to the server was lost before the reply arrived, or while reconnecting
+    /// when the request was made.
+    Disconnected,
+    /// The server won't answer the request, because an operator muted this client or the server
+    /// doesn't take requests.
+    Refused,
 }
 
 /// Trait implemented by a struct to define customizable functionality for a synchronous terminal client.
@@ -39,9 +130,10 @@ where
     ///
     /// # Examples
     /// ```
-    /// # use syncterm::Key;
-    /// # use syncterm::client::KeyAction;
-    ///
+    /// # use syncterm::client::{Key, KeyAction};
+    /// # type Message = String;
+    /// # struct App { input_buffer: String }
+    /// # impl App {
     /// fn on_key(&mut self, key: Key) -> KeyAction<Message> {
     ///        match key {
     ///            Key::Ctrl('c') | Key::Esc => {
@@ -62,32 +154,300 @@ where
     ///
     ///        KeyAction::DoNothing
     ///    }
+    /// # }
     /// ```
     fn on_key(&mut self, key: Key) -> KeyAction<M>;
 
+    /// Returns which parts of the terminal's lifecycle `connect` should manage: raw mode, the
+    /// alternate screen, hiding the cursor and mouse reporting. Whatever is set up is restored on
+    /// exit, including when the client panics. Defaults to managing nothing.
+    fn terminal_options(&self) -> TerminalOptions {
+        TerminalOptions::default()
+    }
+
+    /// Returns where `connect` should log syncterm's diagnostics, the app's own `log` records and
+    /// any panic, since nothing can be printed while the client owns the terminal. Defaults to
+    /// `LogOptions::from_env`, which logs to the file in `SYNCTERM_LOG`, if it's set.
+    fn log_options(&self) -> Option<LogOptions> {
+        LogOptions::from_env()
+    }
+
+    /// Whether to enable mouse reporting in the terminal, so that `on_mouse` receives clicks and
+    /// scrolling. Defaults to `false`.
+    fn capture_mouse(&self) -> bool {
+        false
+    }
+
+    /// Given a mouse event, defines actions to take. Only called if `capture_mouse` is `true`.
+    /// Returns a [KeyAction](enum.KeyAction.html) to signal next library action.
+    ///
+    /// # Examples
+    /// ```
+    /// # use syncterm::client::{KeyAction, MouseButton, MouseEvent};
+    /// # type Message = (u16, u16);
+    /// # struct App();
+    /// # impl App {
+    /// fn on_mouse(&mut self, mouse: MouseEvent) -> KeyAction<Message> {
+    ///     match mouse {
+    ///         MouseEvent::Press(MouseButton::Left, x, y) => KeyAction::SendMessage((x, y)),
+    ///         _ => KeyAction::DoNothing,
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    fn on_mouse(&mut self, _mouse: MouseEvent) -> KeyAction<M> {
+        KeyAction::DoNothing
+    }
+
+    /// Given any input event, defines actions to take. Defaults to passing key presses to
+    /// `on_key` and mouse events to `on_mouse`, and ignoring anything else.
+    fn on_event(&mut self, event: Event) -> KeyAction<M> {
+        match event {
+            Event::Key(key) => self.on_key(key),
+            Event::Mouse(mouse) => self.on_mouse(mouse),
+            Event::Unsupported(_) => KeyAction::DoNothing,
+        }
+    }
+
     /// When client receives a response from the server, defines any actions to take.
     ///
     /// # Examples
     /// ```no_run
+    /// # struct App { messages: Vec<String> }
+    /// # impl App {
     /// fn receive_response(&mut self, response: String) {
     ///     self.messages.push(response);
     ///}
+    /// # }
     /// ```
     ///
     fn receive_response(&mut self, server_response: R);
 
+    /// When client receives the reply to one of its own requests, or the request times out,
+    /// defines any actions to take.
+    ///
+    /// Defaults to passing successful replies on to `receive_response`.
+    fn receive_reply(&mut self, _id: RequestId, reply: Result<R, RequestError>) {
+        if let Ok(server_response) = reply {
+            self.receive_response(server_response);
+        }
+    }
+
+    /// When a synced server's state changes, receives the client's up-to-date copy of it.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # struct Board { moves: Vec<String> }
+    /// # struct App { last_move: Option<String> }
+    /// # impl App {
+    /// fn on_state(&mut self, board: &Board) {
+    ///     self.last_move = board.moves.last().cloned();
+    /// }
+    /// # }
+    /// ```
+    fn on_state(&mut self, _state: &R) {}
+
+    /// Applies one of the client's own inputs to its copy of a synced server's state, ahead of the
+    /// server doing so. Returns whether the input was applied.
+    ///
+    /// Predicted inputs are re-applied on top of every state the server sends, until the server
+    /// has processed them, and the result passed to `on_state`. Defaults to predicting nothing.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # struct Board { moves: Vec<String> }
+    /// // Shared with the server's `SyncedServer::process_input`
+    /// fn apply(board: &mut Board, input: &str) {
+    ///     board.moves.push(input.to_owned());
+    /// }
+    ///
+    /// # struct App();
+    /// # impl App {
+    /// fn predict(&self, board: &mut Board, input: &String) -> bool {
+    ///     apply(board, input);
+    ///     true
+    /// }
+    /// # }
+    /// ```
+    fn predict(&self, _state: &mut R, _input: &M) -> bool {
+        false
+    }
+
+    /// Returns how to smooth out the synced server states passed to `on_state`, if at all.
+    ///
+    /// When interpolating, the UI is redrawn every `frame_interval` (or 60 times a second, if
+    /// that is `None`) rather than only after events, and the states predicted by `predict` only
+    /// show once the server has sent a state after them. Defaults to `None`.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use syncterm::interpolation::{Interpolation, Lerp};
+    /// # struct Ball { x: f64 }
+    /// # impl Lerp for Ball {
+    /// #     fn lerp(&self, other: &Ball, t: f64) -> Ball { Ball { x: self.x + (other.x - self.x) * t } }
+    /// # }
+    /// # struct App();
+    /// # impl App {
+    /// fn interpolation(&self) -> Option<Interpolation<Ball>> {
+    ///     Some(Interpolation::new(Duration::from_millis(100)))
+    /// }
+    /// # }
+    /// ```
+    fn interpolation(&self) -> Option<Interpolation<R>> {
+        None
+    }
+
+    /// In line mode, given a line of input, defines actions to take. Defaults to `None`, which
+    /// hands the line to `on_key` a character at a time, followed by Enter.
+    ///
+    /// # Examples
+    /// ```
+    /// # use syncterm::client::KeyAction;
+    /// # struct App;
+    /// # impl App {
+    /// fn on_line(&mut self, line: &str) -> Option<KeyAction<String>> {
+    ///     Some(KeyAction::SendMessage(line.to_owned()))
+    /// }
+    /// # }
+    /// ```
+    fn on_line(&mut self, _line: &str) -> Option<KeyAction<M>> {
+        None
+    }
+
+    /// In line mode, returns the line to print for a response, if any. Defaults to `None`, which
+    /// prints nothing.
+    fn response_line(&self, _response: &R) -> Option<String> {
+        None
+    }
+
+    /// Returns how to hold on to inputs when the connection to the server is lost, reconnecting
+    /// in the background. Defaults to `None`, which exits the client instead.
+    ///
+    /// # Examples
+    /// ```
+    /// # use syncterm::outbox::Outbox;
+    /// # struct App;
+    /// # impl App {
+    /// fn outbox(&self) -> Option<Outbox> {
+    ///     Some(Outbox::new(100).persist("outbox.jsonl"))
+    /// }
+    /// # }
+    /// ```
+    fn outbox(&self) -> Option<Outbox> {
+        None
+    }
+
+    /// When an input sent through the client's outbox is queued, sent, acknowledged by the
+    /// server or dropped, defines any actions to take.
+    ///
+    /// Every input's first delivery report (queued, sent or dropped) is made straight away, before
+    /// the next input is sent, so ids can be matched up with inputs in the order they're sent.
+    fn on_delivery(&mut self, _id: MessageId, _delivery: Delivery) {}
+
+    /// When the connection to the server is lost or recovers, defines any actions to take. Only
+    /// called for clients with an outbox.
+    fn on_connection(&mut self, _connected: bool) {}
+
+    /// When a lockstep server starts a new turn, defines any actions to take. The turn's inputs
+    /// arrive later, through `receive_response`.
+    ///
+    /// Inputs sent from then on are for this turn, unless sent with `KeyAction::SendForTurn`.
+    fn on_turn_start(&mut self, _turn: TurnInfo) {}
+
+    /// Returns how often to tick and redraw the client UI between events, if at all, for
+    /// animations and timers. Defaults to `None`, which only redraws after events.
+    fn frame_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Returns the most times per second to redraw the client UI, if it should be limited. Defaults
+    /// to `None`, which redraws after every batch of events.
+    ///
+    /// Events that arrive in between are still handed to the client straight away; the UI is
+    /// redrawn once the interval is up.
+    fn max_redraw_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the key that shows or hides a debug overlay on top of the client UI, with the
+    /// round-trip time, traffic and send queue of the connection to the server. Defaults to
+    /// `None`, which leaves the key to the client and collects no statistics.
+    ///
+    /// The key is never passed to `on_key`. The statistics are updated once a second.
+    fn debug_overlay_key(&self) -> Option<Key> {
+        None
+    }
+
+    /// Whether to collect the statistics the debug overlay shows, for the client's own use,
+    /// through `on_stats`. Defaults to `false`.
+    fn collect_stats(&self) -> bool {
+        false
+    }
+
+    /// Receives the network statistics of the connection to the server, once a second, if
+    /// `collect_stats` is `true`. The UI is redrawn afterwards.
+    fn on_stats(&mut self, _stats: &NetStats) {}
+
+    /// Called when the screen has been cleared from under the client UI, such as when the debug
+    /// overlay is hidden, before the UI is redrawn. Clients that only draw what changed since
+    /// their last draw, as `tui::Terminal` does, should draw everything next time.
+    ///
+    /// # Examples
+    /// ```
+    /// # extern crate syncterm;
+    /// # extern crate tui;
+    /// # use tui::Terminal;
+    /// # use tui::backend::MouseBackend;
+    /// # use tui::layout::Rect;
+    /// # struct App { size: Rect, terminal: Terminal<MouseBackend> }
+    /// # impl App {
+    /// fn on_invalidate(&mut self) {
+    ///     // Throws away what the terminal last drew
+    ///     self.terminal.resize(self.size).unwrap();
+    /// }
+    /// # }
+    /// # fn main() {}
+    /// ```
+    fn on_invalidate(&mut self) {}
+
+    /// Called once per frame interval, before the UI is redrawn, with the time since the previous
+    /// tick.
+    ///
+    /// # Examples
+    /// ```
+    /// # use std::time::Duration;
+    /// # struct App { countdown: Duration }
+    /// # impl App {
+    /// fn on_tick(&mut self, dt: Duration) {
+    ///     self.countdown = self.countdown.checked_sub(dt).unwrap_or_default();
+    /// }
+    /// # }
+    /// ```
+    fn on_tick(&mut self, _dt: Duration) {}
+
+    /// When the terminal window changes size, receives its new size in columns and rows. The UI
+    /// is redrawn afterwards.
+    ///
+    /// Also called once with the initial size, before `first_draw`.
+    fn on_resize(&mut self, _cols: u16, _rows: u16) {}
+
     /// Does any work to initialize the client UI.
     fn first_draw(&mut self);
 
-    /// Updates the client UI (called in an animation-style update loop).
+    /// Updates the client UI (called in an animation-style update loop, after every event and
+    /// every frame interval).
     ///
     /// # Examples
     /// ```
+    /// # struct App { messages: Vec<String> }
+    /// # impl App {
     /// fn draw(&mut self) {
     ///     if let Some(m) = self.messages.pop() {
     ///         println!("Message: {}", m);
     ///     }
     /// }
+    /// # }
     /// ```
     fn draw(&mut self);
 
@@ -100,70 +460,135 @@ where
 /// Captures stdin, uses the ShellClient to send messages to and receive responses from
 /// a server, and runs an animation update loop to render the UI.
 ///
-/// Returns an error only if the client's `server_url` fails to connect.
+/// If stdin or stdout isn't a terminal, such as when piped, or the `SYNCTERM_LINE_MODE`
+/// environment variable is set, runs in line mode instead: the terminal is left as it is, each
+/// line of stdin is handed to `on_line`, and rather than the client drawing itself, its
+/// `response_line` for each response is printed. At the end of stdin, the client exits once the
+/// server has acknowledged every input and answered every request, or the connection closes.
+///
+/// Returns an error only if the client's `server_url` fails to connect, its log file can't be
+/// opened, or the terminal can't be set up as asked for by its `terminal_options`.
 pub fn connect<C, M, R>(client: C) -> Result<(), String>
 where
     M: Serialize,
     R: DeserializeOwned + Send + 'static,
     C: ShellClient<M, R>,
 {
-    let mut connection = ShellConnection::connect("127.0.0.1:8080")
-        .map_err(|e| format!("Failed to connect to server: {:?}", e))?;
+    if let Some(options) = client.log_options() {
+        logging::init(options)?;
+    }
+
+    let url = client.server_url();
+    let connection = ShellConnection::connect(&url).map_err(|e| {
+        error!("Failed to connect to {}: {:?}", url, e);
+        format!("Failed to connect to server: {:?}", e)
+    })?;
+    info!("Connected to {}", url);
+
+    if is_line_mode() {
+        info!("Not attached to a terminal, so running in line mode");
+        run_stdin(vec![connection], Single(client), true);
+        return Ok(());
+    }
 
-    render(&mut connection, client);
+    let mut terminal_options = client.terminal_options();
+    terminal_options.mouse |= client.capture_mouse();
+    let terminal_session = TerminalSession::enter(terminal_options)
+        .map_err(|e| format!("Failed to set up terminal: {:?}", e))?;
+
+    run_stdin(vec![connection], Single(client), false);
+
+    drop(terminal_session);
     Ok(())
 }
 
-fn render<C, M, R>(connection: &mut ShellConnection, mut client: C)
+/// Whether to run in line mode: if stdin or stdout isn't a terminal, or `SYNCTERM_LINE_MODE` is
+/// set.
+pub(crate) fn is_line_mode() -> bool {
+    env::var_os(LINE_MODE_VAR).is_some()
+        || !termion::is_tty(&io::stdin())
+        || !termion::is_tty(&io::stdout())
+}
+
+/// Runs the client's event loop on stdin: its input events, and the terminal's resizes, or in
+/// line mode, its lines.
+pub(crate) fn run_stdin<C, M, R>(connections: Vec<ShellConnection>, mut client: C, line_mode: bool)
 where
     M: Serialize,
     R: DeserializeOwned + Send + 'static,
-    C: ShellClient<M, R>,
+    C: Frontend<M, R>,
 {
-    // Input thread
+    let (resize_tx, resize_rx) = chan::sync(0);
+    // Never closed, even if the resize thread exits or there is none, or selecting on it would
+    // spin
+    let _resize_tx = resize_tx.clone();
+
+    // Input thread, which in line mode closes the channel at the end of stdin
     let (input_tx, input_rx) = chan::sync(0);
+    if line_mode {
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => input_tx.send(UserInput::Line(line)),
+                    Err(e) => {
+                        error!("Failed to read from stdin: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        return session::run(
+            connections,
+            client,
+            input_rx,
+            resize_rx,
+            Output::Lines,
+            |_| None,
+        );
+    }
+
     thread::spawn(move || {
         let stdin = io::stdin();
-        for c in stdin.keys() {
+        for c in stdin.events() {
             let evt = c.unwrap();
-            input_tx.send(evt);
+            input_tx.send(UserInput::Event(evt));
         }
     });
 
-    // Connection reading thread
-    let (response_tx, response_rx) = chan::sync(0);
-    let read_connection = connection.try_clone().unwrap();
-    thread::spawn(move || loop {
-        match read_connection.read_response() {
-            Ok(resp) => response_tx.send(resp),
-            Err(_) => break,
-        };
-    });
+    // Terminal resize thread
+    thread::spawn(move || watch_terminal_size(resize_tx));
 
-    client.first_draw();
+    if let Ok((cols, rows)) = termion::terminal_size() {
+        client.on_resize(cols, rows);
+    }
 
-    loop {
-        chan_select! {
-            input_rx.recv() -> key => {
-                match client.on_key(key.unwrap()) {
-                    KeyAction::DoNothing => {}
-                    KeyAction::Exit => break,
-                    KeyAction::SendMessage(msg) => {
-                        connection.send_input(msg).unwrap();
-                    }
-                }
-            },
-            response_rx.recv() -> response => {
-                if let Some(response) = response {
-                    client.receive_response(response);
-                } else {
-                    break;
-                }
-            },
-        }
+    session::run(
+        connections,
+        client,
+        input_rx,
+        resize_rx,
+        Output::Terminal,
+        |_| None,
+    );
+}
 
-        client.draw();
-    }
+/// Sends the terminal's size whenever the window changes size (on SIGWINCH).
+fn watch_terminal_size(resize_tx: chan::Sender<(u16, u16)>) {
+    let mut signals = match Signals::new([SIGWINCH]) {
+        Ok(signals) => signals,
+        Err(_) => return,
+    };
 
-    client.last_draw();
+    let mut size = termion::terminal_size().ok();
+    for _ in signals.forever() {
+        let new_size = termion::terminal_size().ok();
+        if new_size != size {
+            size = new_size;
+            if let Some(size) = size {
+                resize_tx.send(size);
+            }
+        }
+    }
 }
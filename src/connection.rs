@@ -0,0 +1,262 @@
+//! Talks to a ShellServer straight from a Rust program, without a `ShellClient` or a terminal.
+//!
+//! # Examples
+//! ```no_run
+//! # extern crate syncterm;
+//! # use syncterm::connection::Connection;
+//! # fn main() -> Result<(), String> {
+//! let mut connection = Connection::<String, String>::connect("127.0.0.1:8080")?;
+//! connection.send(&"Build #42 passed".to_owned())?;
+//!
+//! for response in connection.iter() {
+//!     println!("{}", response);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use protocol::{ClientFrame, ServerFrame};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+use shell_connection::ShellConnection;
+
+/// A connection to a ShellServer, sending it messages of type M and receiving responses of type
+/// R: whatever the server broadcasts, and its replies to this connection's requests.
+pub struct Connection<M, R> {
+    reader: Reader<R>,
+    writer: Writer<M>,
+}
+
+impl<M, R> Connection<M, R>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+{
+    /// Connects to the server at `url`.
+    pub fn connect(url: &str) -> Result<Self, String> {
+        let connection = ShellConnection::connect(url)
+            .map_err(|e| format!("Failed to connect to server: {:?}", e))?;
+
+        Self::from_shell_connection(connection)
+    }
+
+    /// Reads responses on a thread of their own, skipping any frame that can't be decoded, until
+    /// reading fails or the connection closes.
+    fn from_shell_connection(connection: ShellConnection) -> Result<Self, String> {
+        let (response_tx, response_rx) = mpsc::channel();
+        let mut read_connection = connection
+            .try_clone()
+            .map_err(|e| format!("Failed to connect to server: {:?}", e))?;
+        thread::spawn(move || loop {
+            let line = match read_connection.read_line() {
+                Ok(line) => line,
+                Err(e) => {
+                    let _ = response_tx.send(Err(e));
+                    break;
+                }
+            };
+            let response = match serde_json::from_str(&line) {
+                Ok(ServerFrame::Broadcast(response)) | Ok(ServerFrame::Reply(_, response)) => {
+                    response
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("Skipped a frame that couldn't be decoded: {:?}", e);
+                    continue;
+                }
+            };
+            if response_tx.send(Ok(response)).is_err() {
+                break;
+            }
+        });
+
+        Ok(Self {
+            reader: Reader {
+                responses: response_rx,
+                closed: None,
+            },
+            writer: Writer {
+                connection,
+                next_seq: 0,
+                message: PhantomData,
+            },
+        })
+    }
+
+    /// Sends a message to the server.
+    pub fn send(&mut self, message: &M) -> Result<(), String> {
+        self.writer.send(message)
+    }
+
+    /// Waits for the next response from the server. Returns an error once the connection closes.
+    pub fn recv(&mut self) -> Result<R, String> {
+        self.reader.recv()
+    }
+
+    /// Returns the next response from the server if one has arrived, without waiting. Returns an
+    /// error once the connection closes.
+    pub fn try_recv(&mut self) -> Result<Option<R>, String> {
+        self.reader.try_recv()
+    }
+
+    /// Returns an iterator that waits for each response from the server, until the connection
+    /// closes.
+    pub fn iter(&mut self) -> Iter<'_, R> {
+        self.reader.iter()
+    }
+
+    /// Splits the connection into halves that can be used independently, such as from separate
+    /// threads.
+    pub fn split(self) -> (Reader<R>, Writer<M>) {
+        (self.reader, self.writer)
+    }
+}
+
+/// The receiving half of a `Connection`.
+pub struct Reader<R> {
+    responses: Receiver<Result<R, String>>,
+    // Why the connection closed, once it has
+    closed: Option<String>,
+}
+
+impl<R> Reader<R> {
+    /// Waits for the next response from the server. Returns an error once the connection closes.
+    pub fn recv(&mut self) -> Result<R, String> {
+        if let Some(ref e) = self.closed {
+            return Err(e.clone());
+        }
+
+        let response = self
+            .responses
+            .recv()
+            .unwrap_or_else(|_| Err("Connection closed".to_owned()));
+        self.closed = response.as_ref().err().cloned();
+        response
+    }
+
+    /// Returns the next response from the server if one has arrived, without waiting. Returns an
+    /// error once the connection closes.
+    pub fn try_recv(&mut self) -> Result<Option<R>, String> {
+        if let Some(ref e) = self.closed {
+            return Err(e.clone());
+        }
+
+        let response = match self.responses.try_recv() {
+            Ok(response) => response,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => Err("Connection closed".to_owned()),
+        };
+        self.closed = response.as_ref().err().cloned();
+        response.map(Some)
+    }
+
+    /// Returns an iterator that waits for each response from the server, until the connection
+    /// closes.
+    pub fn iter(&mut self) -> Iter<'_, R> {
+        Iter { reader: self }
+    }
+}
+
+impl<R> IntoIterator for Reader<R> {
+    type Item = R;
+    type IntoIter = IntoIter<R>;
+
+    fn into_iter(self) -> IntoIter<R> {
+        IntoIter { reader: self }
+    }
+}
+
+/// Waits for each response from the server, until the connection closes.
+pub struct Iter<'a, R: 'a> {
+    reader: &'a mut Reader<R>,
+}
+
+impl<'a, R> Iterator for Iter<'a, R> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        self.reader.recv().ok()
+    }
+}
+
+/// Waits for each response from the server, until the connection closes.
+pub struct IntoIter<R> {
+    reader: Reader<R>,
+}
+
+impl<R> Iterator for IntoIter<R> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        self.reader.recv().ok()
+    }
+}
+
+/// The sending half of a `Connection`.
+pub struct Writer<M> {
+    connection: ShellConnection,
+    next_seq: u64,
+    message: PhantomData<fn(M)>,
+}
+
+impl<M: Serialize> Writer<M> {
+    /// Sends a message to the server.
+    pub fn send(&mut self, message: &M) -> Result<(), String> {
+        self.next_seq += 1;
+        self.connection
+            .send_frame(ClientFrame::Input(self.next_seq, message))
+            .map_err(|e| format!("Failed to send: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+    use protocol::RequestId;
+
+    #[test]
+    fn receives_responses_until_the_server_closes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut input = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut input)
+                .unwrap();
+
+            let frames = [
+                serde_json::to_string(&ServerFrame::Broadcast("a")).unwrap(),
+                "not a frame".to_owned(),
+                serde_json::to_string(&ServerFrame::Pong::<&str>(1)).unwrap(),
+                serde_json::to_string(&ServerFrame::Reply(RequestId(3), "b")).unwrap(),
+            ];
+            for frame in &frames {
+                writeln!(stream, "{}", frame).unwrap();
+            }
+            input
+        });
+
+        let mut connection = Connection::<String, String>::connect(&url).unwrap();
+        connection.send(&"hi".to_owned()).unwrap();
+
+        assert_eq!(connection.recv(), Ok("a".to_owned()));
+        assert_eq!(connection.recv(), Ok("b".to_owned()));
+        assert_eq!(connection.recv(), Err("Connection closed".to_owned()));
+        assert_eq!(connection.try_recv(), Err("Connection closed".to_owned()));
+        assert_eq!(connection.iter().next(), None);
+
+        let input: ClientFrame<String> = serde_json::from_str(&server.join().unwrap()).unwrap();
+        match input {
+            ClientFrame::Input(1, ref message) if message == "hi" => {}
+            _ => panic!("unexpected input {:?}", input),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! An editable line of input, for clients to delegate their key presses to from `on_key`.
+//!
+//! Supports cursor movement, word-wise deletion with a yank buffer, history, multi-line input and
+//! bracketed paste, and treats each grapheme cluster (such as an emoji with modifiers, or a
+//! letter with combining accents) as a single character.
+//!
+//! | Key                   | Action                                                   |
+//! |-----------------------|----------------------------------------------------------|
+//! | Left, Right           | Moves the cursor (also Ctrl-B, Ctrl-F)                   |
+//! | Alt-B, Alt-F          | Moves the cursor by a word                               |
+//! | Home, End             | Moves to the start or end of the line (also Ctrl-A, Ctrl-E) |
+//! | Backspace, Delete     | Deletes the character before or after the cursor (also Ctrl-D) |
+//! | Ctrl-W                | Deletes the word before the cursor                       |
+//! | Ctrl-U, Ctrl-K        | Deletes to the start or end of the line                  |
+//! | Ctrl-Y                | Inserts the text last deleted by Ctrl-W, Ctrl-U or Ctrl-K |
+//! | Up, Down              | Moves between lines, or through the history              |
+//! | Enter                 | Submits the input                                        |
+//! | Alt-Enter             | Inserts a newline, if multi-line input is enabled        |
+//!
+//! # Examples
+//! ```
+//! # use syncterm::client::{Key, KeyAction};
+//! # use syncterm::editor::{Edit, LineEditor};
+//! # struct App { editor: LineEditor }
+//! # impl App {
+//! fn on_key(&mut self, key: Key) -> KeyAction<String> {
+//!     match self.editor.handle_key(key) {
+//!         Edit::Submit(line) => KeyAction::SendMessage(line),
+//!         Edit::Handled => KeyAction::DoNothing,
+//!         Edit::Unhandled => match key {
+//!             Key::Esc => KeyAction::Exit,
+//!             _ => KeyAction::DoNothing,
+//!         },
+//!     }
+//! }
+//! # }
+//! ```
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use client::{Event, Key};
+
+/// The most submitted lines an editor remembers.
+const HISTORY_LIMIT: usize = 1000;
+
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// What a `LineEditor` did with an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    /// The event isn't one the editor uses, so the client may handle it itself
+    Unhandled,
+    /// The input or the cursor may have changed
+    Handled,
+    /// Enter was pressed, submitting (and clearing) the input
+    Submit(String),
+}
+
+/// A line of input being edited, with a cursor, a yank buffer and a history of submitted lines.
+#[derive(Debug, Clone, Default)]
+pub struct LineEditor {
+    input: String,
+    // A byte index into `input`, always on a grapheme boundary
+    cursor: usize,
+    yanked: String,
+    history: Vec<String>,
+    // Which history entry is being shown, and the unsubmitted input to return to after it
+    browsing: Option<(usize, String)>,
+    multiline: bool,
+    pasting: bool,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows newlines in the input, inserted with Alt-Enter or by pasting. Otherwise, pasted
+    /// newlines become spaces.
+    pub fn multiline(mut self, multiline: bool) -> Self {
+        self.multiline = multiline;
+        self
+    }
+
+    /// The input so far.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    /// Replaces the input, moving the cursor to its end.
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.to_owned();
+        self.cursor = self.input.len();
+        self.browsing = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.set_input("");
+    }
+
+    /// The cursor's position, as a line and a column counted in graphemes, from 0.
+    pub fn cursor(&self) -> (usize, usize) {
+        let before = &self.input[..self.cursor];
+        let line = before.matches('\n').count();
+        let column = before[self.line_start()..].graphemes(true).count();
+        (line, column)
+    }
+
+    /// The submitted lines, oldest first.
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Replaces the history, such as with one saved by a previous run. Oldest first.
+    pub fn set_history(&mut self, history: Vec<String>) {
+        self.history = history;
+        self.browsing = None;
+    }
+
+    /// Edits the input with an event. Like `handle_key`, but also recognises bracketed pastes,
+    /// if `TerminalOptions::bracketed_paste` is enabled.
+    pub fn handle_event(&mut self, event: Event) -> Edit {
+        match event {
+            Event::Key(key) => self.handle_key(key),
+            Event::Unsupported(ref bytes) if bytes.as_slice() == PASTE_START => {
+                self.pasting = true;
+                Edit::Handled
+            }
+            Event::Unsupported(ref bytes) if bytes.as_slice() == PASTE_END => {
+                self.pasting = false;
+                Edit::Handled
+            }
+            _ => Edit::Unhandled,
+        }
+    }
+
+    /// Edits the input with a key press.
+    pub fn handle_key(&mut self, key: Key) -> Edit {
+        match key {
+            Key::Char('\n') if self.pasting => self.insert(if self.multiline { "\n" } else { " " }),
+            Key::Char('\n') => return Edit::Submit(self.submit()),
+            Key::Alt('\r') | Key::Alt('\n') if self.multiline => self.insert("\n"),
+            Key::Char(c) => self.insert(c.encode_utf8(&mut [0; 4])),
+
+            Key::Left | Key::Ctrl('b') => self.cursor = self.prev_grapheme(),
+            Key::Right | Key::Ctrl('f') => self.cursor = self.next_grapheme(),
+            Key::Alt('b') => self.cursor = self.prev_word(),
+            Key::Alt('f') => self.cursor = self.next_word(),
+            Key::Home | Key::Ctrl('a') => self.cursor = self.line_start(),
+            Key::End | Key::Ctrl('e') => self.cursor = self.line_end(),
+            Key::Up => self.up(),
+            Key::Down => self.down(),
+
+            Key::Backspace => {
+                let start = self.prev_grapheme();
+                self.delete(start, self.cursor);
+            }
+            Key::Delete | Key::Ctrl('d') => {
+                let end = self.next_grapheme();
+                self.delete(self.cursor, end);
+            }
+            Key::Ctrl('w') => {
+                let start = self.prev_word();
+                self.kill(start, self.cursor);
+            }
+            Key::Ctrl('u') => {
+                let start = self.line_start();
+                self.kill(start, self.cursor);
+            }
+            Key::Ctrl('k') => {
+                // At the end of a line, joins the next one onto it
+                let end = match self.line_end() {
+                    end if end == self.cursor => self.next_grapheme(),
+                    end => end,
+                };
+                self.kill(self.cursor, end);
+            }
+            Key::Ctrl('y') => {
+                let yanked = self.yanked.clone();
+                self.insert(&yanked);
+            }
+
+            _ => return Edit::Unhandled,
+        }
+
+        Edit::Handled
+    }
+
+    fn submit(&mut self) -> String {
+        let input = self.input.split_off(0);
+        self.cursor = 0;
+        self.browsing = None;
+
+        if !input.is_empty() && self.history.last() != Some(&input) {
+            self.history.push(input.clone());
+            if self.history.len() > HISTORY_LIMIT {
+                self.history.remove(0);
+            }
+        }
+        input
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.input.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn delete(&mut self, start: usize, end: usize) {
+        self.input.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    fn kill(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.yanked = self.input[start..end].to_owned();
+            self.delete(start, end);
+        }
+    }
+
+    fn prev_grapheme(&self) -> usize {
+        self.input[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn next_grapheme(&self) -> usize {
+        self.input[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map_or(self.cursor, |g| self.cursor + g.len())
+    }
+
+    /// The start of the word before the cursor, skipping any whitespace in between.
+    fn prev_word(&self) -> usize {
+        let mut start = self.cursor;
+        let mut in_word = false;
+        for (i, g) in self.input[..self.cursor].grapheme_indices(true).rev() {
+            let space = g.chars().all(char::is_whitespace);
+            if space && in_word {
+                break;
+            }
+            in_word |= !space;
+            start = i;
+        }
+        start
+    }
+
+    /// The end of the word after the cursor, skipping any whitespace in between.
+    fn next_word(&self) -> usize {
+        let mut end = self.cursor;
+        let mut in_word = false;
+        for g in self.input[self.cursor..].graphemes(true) {
+            let space = g.chars().all(char::is_whitespace);
+            if space && in_word {
+                break;
+            }
+            in_word |= !space;
+            end += g.len();
+        }
+        end
+    }
+
+    fn line_start(&self) -> usize {
+        self.input[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.input[self.cursor..]
+            .find('\n')
+            .map_or(self.input.len(), |i| self.cursor + i)
+    }
+
+    fn up(&mut self) {
+        let start = self.line_start();
+        if start == 0 {
+            return self.browse_back();
+        }
+
+        let column = self.input[start..self.cursor].graphemes(true).count();
+        let prev_start = self.input[..start - 1].rfind('\n').map_or(0, |i| i + 1);
+        self.cursor = self.column_in_line(prev_start, column);
+    }
+
+    fn down(&mut self) {
+        let end = self.line_end();
+        if end == self.input.len() {
+            return self.browse_forward();
+        }
+
+        let column = self.input[self.line_start()..self.cursor]
+            .graphemes(true)
+            .count();
+        self.cursor = self.column_in_line(end + 1, column);
+    }
+
+    /// The index `column` graphemes into the line starting at `start`, or the line's end.
+    fn column_in_line(&self, start: usize, column: usize) -> usize {
+        let line = self.input[start..].split('\n').next().unwrap_or("");
+        start
+            + line
+                .graphemes(true)
+                .take(column)
+                .map(str::len)
+                .sum::<usize>()
+    }
+
+    fn browse_back(&mut self) {
+        let entry = match self.browsing {
+            Some((0, _)) => return,
+            Some((entry, _)) => entry - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => self.input.clone(),
+        };
+        self.input = self.history[entry].clone();
+        self.cursor = self.input.len();
+        self.browsing = Some((entry, draft));
+    }
+
+    fn browse_forward(&mut self) {
+        let (entry, draft) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => return,
+        };
+
+        if entry + 1 < self.history.len() {
+            self.input = self.history[entry + 1].clone();
+            self.browsing = Some((entry + 1, draft));
+        } else {
+            self.input = draft;
+        }
+        self.cursor = self.input.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            editor.handle_key(Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn edits_around_the_cursor() {
+        let mut editor = LineEditor::new();
+        typed(&mut editor, "helo");
+        editor.handle_key(Key::Left);
+        typed(&mut editor, "l");
+        assert_eq!((editor.input(), editor.cursor()), ("hello", (0, 4)));
+
+        editor.handle_key(Key::Home);
+        editor.handle_key(Key::Delete);
+        editor.handle_key(Key::End);
+        editor.handle_key(Key::Backspace);
+        assert_eq!((editor.input(), editor.cursor()), ("ell", (0, 3)));
+    }
+
+    #[test]
+    fn treats_graphemes_as_single_characters() {
+        let mut editor = LineEditor::new();
+        editor.set_input("ae\u{301}👍🏽");
+        assert_eq!(editor.cursor(), (0, 3));
+
+        editor.handle_key(Key::Backspace);
+        editor.handle_key(Key::Left);
+        editor.handle_key(Key::Delete);
+        assert_eq!(editor.input(), "a");
+    }
+
+    #[test]
+    fn yanks_what_was_last_killed() {
+        let mut editor = LineEditor::new();
+        editor.set_input("git commit  --amend");
+
+        editor.handle_key(Key::Ctrl('w'));
+        assert_eq!(editor.input(), "git commit  ");
+        editor.handle_key(Key::Alt('b'));
+        editor.handle_key(Key::Ctrl('k'));
+        assert_eq!(editor.input(), "git ");
+
+        editor.handle_key(Key::Ctrl('a'));
+        editor.handle_key(Key::Ctrl('y'));
+        assert_eq!(editor.input(), "commit  git ");
+        assert_eq!(editor.handle_key(Key::Esc), Edit::Unhandled);
+    }
+
+    #[test]
+    fn submits_into_the_history() {
+        let mut editor = LineEditor::new();
+        for line in &["one", "two", "two", ""] {
+            typed(&mut editor, line);
+            assert_eq!(
+                editor.handle_key(Key::Char('\n')),
+                Edit::Submit(line.to_string())
+            );
+        }
+        assert_eq!(editor.history(), &["one".to_owned(), "two".to_owned()]);
+        assert_eq!(editor.input(), "");
+    }
+
+    #[test]
+    fn browses_the_history_and_back_to_the_draft() {
+        let mut editor = LineEditor::new();
+        editor.set_history(vec!["one".to_owned(), "two".to_owned()]);
+        typed(&mut editor, "dra");
+
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.input(), "two");
+        editor.handle_key(Key::Up);
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.input(), "one");
+
+        editor.handle_key(Key::Down);
+        assert_eq!(editor.input(), "two");
+        editor.handle_key(Key::Down);
+        assert_eq!((editor.input(), editor.cursor()), ("dra", (0, 3)));
+    }
+
+    #[test]
+    fn moves_between_lines_when_multiline() {
+        let mut editor = LineEditor::new().multiline(true);
+        typed(&mut editor, "first");
+        editor.handle_key(Key::Alt('\r'));
+        typed(&mut editor, "2nd");
+        assert_eq!(editor.cursor(), (1, 3));
+
+        editor.handle_key(Key::Up);
+        assert_eq!(editor.cursor(), (0, 3));
+        editor.handle_key(Key::End);
+        editor.handle_key(Key::Down);
+        assert_eq!(editor.cursor(), (1, 3));
+
+        editor.handle_key(Key::Home);
+        editor.handle_key(Key::Backspace);
+        assert_eq!(editor.input(), "first2nd");
+    }
+
+    #[test]
+    fn pastes_newlines_without_submitting() {
+        let paste = |editor: &mut LineEditor| {
+            editor.handle_event(Event::Unsupported(PASTE_START.to_vec()));
+            typed(editor, "a\nb");
+            editor.handle_event(Event::Unsupported(PASTE_END.to_vec()));
+            editor.input().to_owned()
+        };
+
+        assert_eq!(paste(&mut LineEditor::new()), "a b");
+        assert_eq!(paste(&mut LineEditor::new().multiline(true)), "a\nb");
+    }
+}
@@ -0,0 +1,333 @@
+//! Runs clients without a terminal, for bots, automated players and soak tests.
+//!
+//! Instead of stdin, a headless client's input events come from any iterator of `Input`s: a
+//! `Vec`, a channel's receiver or a script file read with `script`.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use chan;
+
+use client::{Event, Key, KeyAction, ShellClient};
+use keymap::parse_key;
+use serde::{Serialize, de::DeserializeOwned};
+use session::{self, Output, Single, UserInput};
+use shell_connection::ShellConnection;
+
+/// An input to a headless client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// Hands the client an input event, as if it were read from the terminal
+    Event(Event),
+    /// Waits before handing the client any further inputs
+    Wait(Duration),
+    /// Exits the client, as if it returned `KeyAction::Exit`
+    Exit,
+}
+
+impl From<Event> for Input {
+    fn from(event: Event) -> Self {
+        Input::Event(event)
+    }
+}
+
+impl From<Key> for Input {
+    fn from(key: Key) -> Self {
+        Input::Event(Event::Key(key))
+    }
+}
+
+/// A client with no UI, which reacts to its inputs and the server's responses with actions.
+pub trait BotClient<M, R>
+where
+    M: Serialize,
+    R: DeserializeOwned,
+{
+    /// Returns the URL of the server to connect to.
+    fn server_url(&self) -> String;
+
+    /// Defines any actions to take once connected, before any inputs.
+    fn on_start(&mut self) -> KeyAction<M> {
+        KeyAction::DoNothing
+    }
+
+    /// Defines the actions to take on an input event.
+    fn on_event(&mut self, _event: Event) -> KeyAction<M> {
+        KeyAction::DoNothing
+    }
+
+    /// Defines the actions to take when a message is received from the server.
+    fn on_response(&mut self, response: R) -> KeyAction<M>;
+
+    /// Returns how often to call `on_tick`, if at all. Defaults to `None`.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Defines the actions to take once per tick interval, given the time since the previous tick.
+    fn on_tick(&mut self, _dt: Duration) -> KeyAction<M> {
+        KeyAction::DoNothing
+    }
+}
+
+/// Runs a ShellClient without a terminal, handing it `inputs` in place of stdin, until the client
+/// exits, an `Input::Exit` is reached or the server closes the connection. Once the inputs run
+/// out, the client keeps receiving responses.
+///
+/// The client's `draw` methods are never called, and nor is `on_resize`.
+///
+/// Returns an error only if the client's `server_url` fails to connect.
+pub fn run_headless<C, M, R, I>(client: C, inputs: I) -> Result<(), String>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: ShellClient<M, R>,
+    I: IntoIterator<Item = Input>,
+    I::IntoIter: Send + 'static,
+{
+    let connection = ShellConnection::connect(&client.server_url())
+        .map_err(|e| format!("Failed to connect to server: {:?}", e))?;
+
+    let (input_rx, _done_tx) = feed(inputs);
+    // Never sent on, since there is no terminal to resize
+    let (_resize_tx, resize_rx) = chan::sync(0);
+
+    session::run(
+        vec![connection],
+        Single(client),
+        input_rx,
+        resize_rx,
+        Output::Hidden,
+        |_| None,
+    );
+    Ok(())
+}
+
+/// Adapts a BotClient to the ShellClient event loop, queueing up the actions it returns in
+/// reaction to anything but input events.
+struct Bot<B, M: Serialize> {
+    bot: B,
+    actions: VecDeque<KeyAction<M>>,
+}
+
+impl<B, M, R> ShellClient<M, R> for Bot<B, M>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send,
+    B: BotClient<M, R>,
+{
+    fn server_url(&self) -> String {
+        self.bot.server_url()
+    }
+
+    fn on_key(&mut self, key: Key) -> KeyAction<M> {
+        self.bot.on_event(Event::Key(key))
+    }
+
+    fn on_event(&mut self, event: Event) -> KeyAction<M> {
+        self.bot.on_event(event)
+    }
+
+    fn receive_response(&mut self, response: R) {
+        let action = self.bot.on_response(response);
+        self.actions.push_back(action);
+    }
+
+    fn frame_interval(&self) -> Option<Duration> {
+        self.bot.tick_interval()
+    }
+
+    fn on_tick(&mut self, dt: Duration) {
+        let action = self.bot.on_tick(dt);
+        self.actions.push_back(action);
+    }
+
+    fn first_draw(&mut self) {}
+
+    fn draw(&mut self) {}
+
+    fn last_draw(&mut self) {}
+}
+
+/// Runs a BotClient, handing it `inputs` as `run_headless` does.
+///
+/// Returns an error only if the bot's `server_url` fails to connect.
+pub fn run_bot<B, M, R, I>(bot: B, inputs: I) -> Result<(), String>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    B: BotClient<M, R>,
+    I: IntoIterator<Item = Input>,
+    I::IntoIter: Send + 'static,
+{
+    let mut bot = Bot {
+        bot,
+        actions: VecDeque::new(),
+    };
+    let start = bot.bot.on_start();
+    bot.actions.push_back(start);
+
+    let connection = ShellConnection::connect(&bot.server_url())
+        .map_err(|e| format!("Failed to connect to server: {:?}", e))?;
+
+    let (input_rx, _done_tx) = feed(inputs);
+    // Never sent on, since there is no terminal to resize
+    let (_resize_tx, resize_rx) = chan::sync(0);
+
+    session::run(
+        vec![connection],
+        Single(bot),
+        input_rx,
+        resize_rx,
+        Output::Hidden,
+        |bot| bot.0.actions.pop_front(),
+    );
+    Ok(())
+}
+
+/// Reads a script of inputs from a file, one per line:
+///
+/// ```text
+/// # Lines starting with '#' are comments
+/// text hello      # a Char key for each character
+/// key enter       # a single key: a character, or enter, esc, tab, backspace, delete, insert,
+///                 # up, down, left, right, home, end, pageup, pagedown, f1-f12, ctrl-c, alt-x
+/// wait 250        # waits 250ms
+/// exit
+/// ```
+///
+/// Returns an error naming the first line that fails to parse.
+pub fn script(path: &str) -> Result<Vec<Input>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {:?}", path, e))?;
+
+    let mut inputs = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        parse_line(line, &mut inputs).map_err(|e| format!("{}:{}: {}", path, number + 1, e))?;
+    }
+
+    Ok(inputs)
+}
+
+fn parse_line(line: &str, inputs: &mut Vec<Input>) -> Result<(), String> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+
+    let (command, arg) = match line.find(' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    };
+
+    match command {
+        "text" => inputs.extend(arg.chars().map(|c| Input::from(Key::Char(c)))),
+        "key" => inputs.push(Input::from(parse_key(arg.trim())?)),
+        "wait" => {
+            let ms = arg
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid wait: {:?}", arg))?;
+            inputs.push(Input::Wait(Duration::from_millis(ms)));
+        }
+        "exit" => inputs.push(Input::Exit),
+        _ => return Err(format!("Unknown command: {:?}", command)),
+    }
+
+    Ok(())
+}
+
+/// Hands the inputs to the client's event loop from their own thread, sleeping through waits.
+///
+/// The returned receiver closes, which exits the loop, on an `Input::Exit`. Otherwise it stays
+/// open, so the loop doesn't spin on it, until the returned sender is dropped.
+fn feed<I>(inputs: I) -> (chan::Receiver<UserInput>, chan::Sender<()>)
+where
+    I: IntoIterator<Item = Input>,
+    I::IntoIter: Send + 'static,
+{
+    let (input_tx, input_rx) = chan::sync(0);
+    let (done_tx, done_rx) = chan::sync::<()>(0);
+
+    let inputs = inputs.into_iter();
+    thread::spawn(move || {
+        for input in inputs {
+            match input {
+                Input::Event(event) => input_tx.send(UserInput::Event(event)),
+                Input::Wait(delay) => thread::sleep(delay),
+                Input::Exit => return,
+            }
+        }
+        done_rx.recv();
+    });
+
+    (input_rx, done_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn parsed(line: &str) -> Result<Vec<Input>, String> {
+        let mut inputs = Vec::new();
+        parse_line(line, &mut inputs).map(|()| inputs)
+    }
+
+    fn keys(keys: &[Key]) -> Vec<Input> {
+        keys.iter().cloned().map(Input::from).collect()
+    }
+
+    #[test]
+    fn parses_script_lines() {
+        assert_eq!(
+            parsed("text a b"),
+            Ok(keys(&[Key::Char('a'), Key::Char(' '), Key::Char('b')]))
+        );
+        assert_eq!(parsed("key enter"), Ok(keys(&[Key::Char('\n')])));
+        assert_eq!(parsed("  key ctrl-c"), Ok(keys(&[Key::Ctrl('c')])));
+        assert_eq!(parsed("key f5"), Ok(keys(&[Key::F(5)])));
+        assert_eq!(parsed("key x"), Ok(keys(&[Key::Char('x')])));
+        assert_eq!(
+            parsed("wait 250"),
+            Ok(vec![Input::Wait(Duration::from_millis(250))])
+        );
+        assert_eq!(parsed("exit"), Ok(vec![Input::Exit]));
+
+        assert_eq!(parsed(""), Ok(vec![]));
+        assert_eq!(parsed("  # key enter"), Ok(vec![]));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in &["jump", "key", "key nope", "key ctrl-", "wait", "wait soon"] {
+            assert!(parsed(line).is_err(), "{:?} parsed", line);
+        }
+    }
+
+    #[test]
+    fn reads_a_script_naming_the_line_that_fails() {
+        let file = env::temp_dir().join(format!("syncterm-script-test-{}.txt", process::id()));
+        let path = file.to_str().unwrap();
+
+        fs::write(path, "# says hi\ntext hi\nwait 10\nkey enter\nexit\n").unwrap();
+        let inputs = script(path);
+        fs::write(path, "text hi\nkey nope\n").unwrap();
+        let failed = script(path);
+        let _ = fs::remove_file(path);
+
+        let mut expected = keys(&[Key::Char('h'), Key::Char('i')]);
+        expected.push(Input::Wait(Duration::from_millis(10)));
+        expected.push(Input::from(Key::Char('\n')));
+        expected.push(Input::Exit);
+        assert_eq!(inputs, Ok(expected));
+
+        let error = failed.unwrap_err();
+        assert!(error.starts_with(&format!("{}:2: ", path)), "{}", error);
+        assert!(script("/nonexistent/script.txt").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Implemented by a synced server's state type to blend between two versions of it.
+pub trait Lerp {
+    /// Returns the state `t` of the way from `self` to `other`, where `t` is between 0 and 1.
+    ///
+    /// # Examples
+    /// ```
+    /// # use syncterm::interpolation::Lerp;
+    /// struct Ball {
+    ///     x: f64,
+    ///     y: f64,
+    /// }
+    ///
+    /// impl Lerp for Ball {
+    ///     fn lerp(&self, other: &Ball, t: f64) -> Ball {
+    ///         Ball {
+    ///             x: self.x + (other.x - self.x) * t,
+    ///             y: self.y + (other.y - self.y) * t,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    fn lerp(&self, other: &Self, t: f64) -> Self;
+}
+
+/// Returned by `ShellClient::interpolation` to smooth out the synced server states passed to
+/// `ShellClient::on_state`.
+///
+/// States are shown `delay` behind the server, blended between the two received states on either
+/// side of that point in time, and `on_state` is called before every frame rather than as states
+/// arrive.
+pub struct Interpolation<R> {
+    delay: Duration,
+    lerp: fn(&R, &R, f64) -> R,
+}
+
+impl<R: Lerp> Interpolation<R> {
+    /// Interpolates states using their `Lerp` implementation, `delay` behind the server. The
+    /// delay should comfortably exceed the time between the server's updates.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            lerp: R::lerp,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch, used to timestamp synced server states.
+pub(crate) fn now_ms() -> f64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_secs_f64() * 1000.0
+}
+
+/// Received states, timestamped by the server, waiting to be interpolated between.
+pub(crate) struct InterpolationBuffer<R> {
+    interpolation: Interpolation<R>,
+    states: VecDeque<(f64, R)>,
+    // How far the server's clock is ahead of ours, less the smallest network delay seen
+    clock_offset: Option<f64>,
+}
+
+impl<R> InterpolationBuffer<R> {
+    pub fn new(interpolation: Interpolation<R>) -> Self {
+        Self {
+            interpolation,
+            states: VecDeque::new(),
+            clock_offset: None,
+        }
+    }
+
+    pub fn push(&mut self, server_time: f64, state: R) {
+        let offset = server_time - now_ms();
+        self.clock_offset = Some(self.clock_offset.map_or(offset, |o| o.max(offset)));
+
+        // Out of order states would confuse the search in `sample`
+        if self.states.back().is_some_and(|&(t, _)| t > server_time) {
+            return;
+        }
+        self.states.push_back((server_time, state));
+    }
+
+    /// The state to show now, if any state has been received.
+    pub fn sample(&mut self) -> Option<R> {
+        let delay = self.interpolation.delay.as_secs_f64() * 1000.0;
+        let render_time = now_ms() + self.clock_offset? - delay;
+
+        // Keep only the latest state from before the render time
+        while self.states.len() > 1 && self.states[1].0 <= render_time {
+            self.states.pop_front();
+        }
+
+        let lerp = self.interpolation.lerp;
+        let (from_time, ref from) = *self.states.front()?;
+        match self.states.get(1) {
+            Some(&(to_time, ref to)) if render_time > from_time => {
+                let t = (render_time - from_time) / (to_time - from_time);
+                Some(lerp(from, to, t.min(1.0)))
+            }
+            _ => Some(lerp(from, from, 0.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct X(f64);
+
+    impl Lerp for X {
+        fn lerp(&self, other: &X, t: f64) -> X {
+            X(self.0 + (other.0 - self.0) * t)
+        }
+    }
+
+    // Long enough that the time the test takes to run barely moves the samples
+    const DELAY_MS: f64 = 1000.0;
+
+    fn buffer() -> InterpolationBuffer<X> {
+        InterpolationBuffer::new(Interpolation::new(Duration::from_millis(DELAY_MS as u64)))
+    }
+
+    fn assert_near(sample: Option<X>, expected: f64) {
+        match sample {
+            Some(X(x)) => assert!((x - expected).abs() < 0.1, "{} isn't {}", x, expected),
+            None => panic!("No sample, expected {}", expected),
+        }
+    }
+
+    #[test]
+    fn samples_nothing_before_any_state() {
+        assert_eq!(buffer().sample(), None);
+    }
+
+    #[test]
+    fn shows_the_first_state_until_the_next_one_is_due() {
+        let mut buffer = buffer();
+        buffer.push(now_ms(), X(3.0));
+        assert_eq!(buffer.sample(), Some(X(3.0)));
+    }
+
+    #[test]
+    fn blends_states_delay_behind_the_server() {
+        let mut buffer = buffer();
+        let now = now_ms();
+        buffer.push(now - 3.0 * DELAY_MS, X(100.0));
+        buffer.push(now - 2.0 * DELAY_MS, X(0.0));
+        buffer.push(now, X(10.0));
+
+        // Halfway between the last two states, having dropped the first
+        assert_near(buffer.sample(), 5.0);
+        assert_eq!(buffer.states.len(), 2);
+    }
+
+    #[test]
+    fn keeps_up_with_a_server_clock_that_runs_ahead() {
+        let mut buffer = buffer();
+        let server_now = now_ms() + 60_000.0;
+        buffer.push(server_now - 2.0 * DELAY_MS, X(0.0));
+        buffer.push(server_now, X(10.0));
+
+        assert_near(buffer.sample(), 5.0);
+    }
+
+    #[test]
+    fn drops_states_that_arrive_out_of_order() {
+        let mut buffer = buffer();
+        let now = now_ms();
+        buffer.push(now, X(1.0));
+        buffer.push(now - DELAY_MS, X(2.0));
+
+        assert_eq!(buffer.states.len(), 1);
+        assert_eq!(buffer.sample(), Some(X(1.0)));
+    }
+}
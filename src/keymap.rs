@@ -0,0 +1,370 @@
+//! Resolves key presses to an app's named actions, through bindings the app declares and its
+//! users can override.
+//!
+//! Bindings map a sequence of one or more keys, written as space-separated key names such as
+//! `"ctrl-c"` or `"ctrl-x ctrl-s"`, to the name of an action. Each belongs to a mode, or to every
+//! mode if declared under `GLOBAL`; bindings in the current mode take precedence.
+//!
+//! Key names are a single character, or one of `enter`, `space`, `tab`, `esc`, `backspace`,
+//! `delete`, `insert`, `up`, `down`, `left`, `right`, `home`, `end`, `pageup`, `pagedown`,
+//! `f1`-`f12`, `ctrl-<char>` and `alt-<char>`.
+//!
+//! Users override bindings with a TOML file, with a table per mode. An empty action name unbinds
+//! a sequence:
+//!
+//! ```toml
+//! [global]
+//! "ctrl-q" = "exit"
+//! "esc" = ""
+//!
+//! [cmd]
+//! "ctrl-x ctrl-l" = "clear"
+//! ```
+//!
+//! # Examples
+//! ```
+//! # use syncterm::client::{Key, KeyAction};
+//! # use syncterm::keymap::{Keymap, Resolved, GLOBAL};
+//! #[derive(Clone)]
+//! enum Action {
+//!     Exit,
+//!     Clear,
+//! }
+//!
+//! let mut keymap = Keymap::new()
+//!     .action("exit", Action::Exit)
+//!     .action("clear", Action::Clear)
+//!     .bind(GLOBAL, "ctrl-c", "exit")
+//!     .bind("cmd", "ctrl-x ctrl-l", "clear");
+//! keymap.set_mode("cmd");
+//!
+//! assert!(match keymap.resolve(Key::Ctrl('x')) {
+//!     Resolved::Pending => true,
+//!     _ => false,
+//! });
+//! assert!(match keymap.resolve(Key::Ctrl('l')) {
+//!     Resolved::Action(Action::Clear) => true,
+//!     _ => false,
+//! });
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+
+use toml;
+
+use client::Key;
+
+/// The mode whose bindings apply in every mode.
+pub const GLOBAL: &str = "global";
+
+/// What a key press resolved to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolved<A> {
+    /// The key completed a bound sequence
+    Action(A),
+    /// The key began or continued a bound sequence, so more keys are needed
+    Pending,
+    /// No bound sequence starts with the keys pressed since the last action, which are returned so
+    /// that the client can handle them itself, such as by passing them to a `LineEditor`
+    Unbound(Vec<Key>),
+}
+
+/// Bindings from key sequences to an app's actions, of type A, and the keys pressed so far
+/// towards one.
+#[derive(Debug, Clone)]
+pub struct Keymap<A> {
+    actions: HashMap<String, A>,
+    // Mode, then key sequence, to action name
+    bindings: HashMap<String, HashMap<Vec<Key>, String>>,
+    mode: String,
+    pending: Vec<Key>,
+}
+
+impl<A: Clone> Default for Keymap<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Clone> Keymap<A> {
+    /// An empty keymap, in the `GLOBAL` mode.
+    pub fn new() -> Self {
+        Self {
+            actions: HashMap::new(),
+            bindings: HashMap::new(),
+            mode: GLOBAL.to_owned(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Declares an action that keys can be bound to by `name`.
+    pub fn action(mut self, name: &str, action: A) -> Self {
+        self.actions.insert(name.to_owned(), action);
+        self
+    }
+
+    /// Binds a key sequence in `mode` to the action named `action`, by default.
+    ///
+    /// # Panics
+    /// If the key sequence or the action name isn't valid, since default bindings are part of
+    /// the app.
+    pub fn bind(mut self, mode: &str, keys: &str, action: &str) -> Self {
+        if let Err(e) = self.set_binding(mode, keys, action) {
+            panic!("Invalid default binding: {}", e);
+        }
+        self
+    }
+
+    /// Overrides the bindings with those in a TOML file.
+    ///
+    /// Returns an error if the file can't be read, or any of its bindings isn't valid, in which
+    /// case none of them are applied.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {:?}", path, e))?;
+        self.load_toml(&contents)
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Overrides the bindings with those in a TOML document.
+    ///
+    /// Returns an error if any of its bindings isn't valid, in which case none of them are
+    /// applied.
+    pub fn load_toml(&mut self, contents: &str) -> Result<(), String> {
+        let tables: HashMap<String, HashMap<String, String>> =
+            toml::from_str(contents).map_err(|e| format!("Invalid keymap: {}", e))?;
+
+        let mut overridden = self.clone();
+        for (mode, bindings) in &tables {
+            for (keys, action) in bindings {
+                overridden.set_binding(mode, keys, action)?;
+            }
+        }
+
+        *self = overridden;
+        Ok(())
+    }
+
+    fn set_binding(&mut self, mode: &str, keys: &str, action: &str) -> Result<(), String> {
+        let keys = keys
+            .split_whitespace()
+            .map(parse_key)
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err("Empty key sequence".to_owned());
+        }
+
+        let bindings = self.bindings.entry(mode.to_owned()).or_default();
+        if action.is_empty() {
+            bindings.remove(&keys);
+        } else if self.actions.contains_key(action) {
+            bindings.insert(keys, action.to_owned());
+        } else {
+            return Err(format!("Unknown action: {:?}", action));
+        }
+
+        Ok(())
+    }
+
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
+
+    /// Switches to the bindings of another mode, abandoning any sequence in progress.
+    pub fn set_mode(&mut self, mode: &str) {
+        self.mode = mode.to_owned();
+        self.pending.clear();
+    }
+
+    /// Resolves a key press, along with those pressed before it towards a sequence.
+    ///
+    /// A sequence resolves as soon as it's complete, so a bound sequence that starts another one
+    /// in the same mode hides it.
+    pub fn resolve(&mut self, key: Key) -> Resolved<A> {
+        self.pending.push(key);
+
+        let mut action = None;
+        let mut pending = false;
+        for mode in &[self.mode.as_str(), GLOBAL] {
+            let bindings = match self.bindings.get(*mode) {
+                Some(bindings) => bindings,
+                None => continue,
+            };
+            if let Some(name) = bindings.get(&self.pending) {
+                action = Some(self.actions[name].clone());
+                break;
+            }
+            pending |= bindings.keys().any(|keys| keys.starts_with(&self.pending));
+        }
+
+        if let Some(action) = action {
+            self.pending.clear();
+            return Resolved::Action(action);
+        }
+        if pending {
+            Resolved::Pending
+        } else {
+            Resolved::Unbound(self.pending.split_off(0))
+        }
+    }
+}
+
+/// Parses a key name, as used in key bindings and headless scripts.
+pub(crate) fn parse_key(name: &str) -> Result<Key, String> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(Key::Char(c));
+    }
+
+    let key = match name {
+        "enter" => Key::Char('\n'),
+        "space" => Key::Char(' '),
+        "tab" => Key::Char('\t'),
+        "esc" => Key::Esc,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        _ => {
+            let modified = |prefix: &str| {
+                let mut chars = name.get(prefix.len()..)?.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if name.starts_with(prefix) => Some(c),
+                    _ => None,
+                }
+            };
+            if let Some(c) = modified("ctrl-") {
+                Key::Ctrl(c)
+            } else if let Some(c) = modified("alt-") {
+                Key::Alt(c)
+            } else if let Some(n) = name
+                .strip_prefix('f')
+                .and_then(|n| n.parse().ok())
+                .filter(|n| (1..=12).contains(n))
+            {
+                Key::F(n)
+            } else {
+                return Err(format!("Unknown key: {:?}", name));
+            }
+        }
+    };
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap() -> Keymap<&'static str> {
+        Keymap::new()
+            .action("exit", "exit")
+            .action("save", "save")
+            .action("clear", "clear")
+            .bind(GLOBAL, "ctrl-c", "exit")
+            .bind(GLOBAL, "ctrl-x ctrl-s", "save")
+            .bind("cmd", "ctrl-c", "clear")
+    }
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!(parse_key("a"), Ok(Key::Char('a')));
+        assert_eq!(parse_key("-"), Ok(Key::Char('-')));
+        assert_eq!(parse_key("enter"), Ok(Key::Char('\n')));
+        assert_eq!(parse_key("pagedown"), Ok(Key::PageDown));
+        assert_eq!(parse_key("ctrl-x"), Ok(Key::Ctrl('x')));
+        assert_eq!(parse_key("alt-é"), Ok(Key::Alt('é')));
+        assert_eq!(parse_key("f12"), Ok(Key::F(12)));
+
+        for name in &[
+            "", "ctrl-", "ctrl-ab", "alt", "fx", "f0", "f13", "f255", "shift-a",
+        ] {
+            assert_eq!(parse_key(name), Err(format!("Unknown key: {:?}", name)));
+        }
+    }
+
+    #[test]
+    fn resolves_sequences() {
+        let mut keymap = keymap();
+
+        assert_eq!(keymap.resolve(Key::Ctrl('c')), Resolved::Action("exit"));
+        assert_eq!(keymap.resolve(Key::Ctrl('x')), Resolved::Pending);
+        assert_eq!(keymap.resolve(Key::Ctrl('s')), Resolved::Action("save"));
+        assert_eq!(
+            keymap.resolve(Key::Ctrl('x')),
+            Resolved::Pending,
+            "a resolved sequence starts over"
+        );
+        assert_eq!(
+            keymap.resolve(Key::Char('q')),
+            Resolved::Unbound(vec![Key::Ctrl('x'), Key::Char('q')])
+        );
+        assert_eq!(
+            keymap.resolve(Key::Char('q')),
+            Resolved::Unbound(vec![Key::Char('q')])
+        );
+    }
+
+    #[test]
+    fn prefers_the_current_modes_bindings() {
+        let mut keymap = keymap();
+        keymap.set_mode("cmd");
+
+        assert_eq!(keymap.resolve(Key::Ctrl('c')), Resolved::Action("clear"));
+        assert_eq!(keymap.resolve(Key::Ctrl('x')), Resolved::Pending);
+        assert_eq!(keymap.resolve(Key::Ctrl('s')), Resolved::Action("save"));
+
+        keymap.resolve(Key::Ctrl('x'));
+        keymap.set_mode(GLOBAL);
+        assert_eq!(keymap.resolve(Key::Ctrl('c')), Resolved::Action("exit"));
+    }
+
+    #[test]
+    fn overrides_bindings_from_toml() {
+        let mut keymap = keymap();
+        keymap
+            .load_toml(
+                r#"
+                [global]
+                "ctrl-q" = "exit"
+                "ctrl-c" = ""
+
+                [cmd]
+                "ctrl-x ctrl-l" = "clear"
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(keymap.resolve(Key::Ctrl('q')), Resolved::Action("exit"));
+        assert_eq!(
+            keymap.resolve(Key::Ctrl('c')),
+            Resolved::Unbound(vec![Key::Ctrl('c')])
+        );
+        keymap.set_mode("cmd");
+        keymap.resolve(Key::Ctrl('x'));
+        assert_eq!(keymap.resolve(Key::Ctrl('l')), Resolved::Action("clear"));
+    }
+
+    #[test]
+    fn applies_no_overrides_if_any_are_invalid() {
+        let mut keymap = keymap();
+
+        let unknown_action = keymap.load_toml("[global]\n\"ctrl-q\" = \"quit\"\n\"ctrl-c\" = \"\"");
+        assert!(unknown_action.is_err());
+        assert!(keymap
+            .load_toml("[global]\n\"hyper-q\" = \"exit\"")
+            .is_err());
+        assert!(keymap.load_toml("[global]\n\" \" = \"exit\"").is_err());
+        assert!(keymap.load_toml("not toml").is_err());
+
+        assert_eq!(keymap.resolve(Key::Ctrl('c')), Resolved::Action("exit"));
+    }
+}
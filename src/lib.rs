@@ -1,10 +1,42 @@
 #[macro_use]
 extern crate chan;
+#[cfg(feature = "widgets")]
+extern crate chrono;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
+extern crate signal_hook;
 extern crate termion;
+extern crate toml;
+#[cfg(feature = "widgets")]
+extern crate tui;
+extern crate unicode_segmentation;
 
+pub mod admin;
 pub mod client;
+pub mod connection;
+pub mod editor;
+pub mod headless;
+pub mod interpolation;
+pub mod keymap;
+pub mod lockstep;
+pub mod logging;
+pub mod metrics;
+pub mod multi;
+pub mod observer;
+pub mod outbox;
+mod prediction;
+mod protocol;
 pub mod server;
+mod session;
 mod shell_connection;
+pub mod stats;
+pub mod sync;
+mod terminal;
+mod timer;
+#[cfg(feature = "widgets")]
+pub mod widgets;
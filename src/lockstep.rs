@@ -0,0 +1,476 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use observer::ServerEvent;
+use protocol::{ClientFrame, ServerFrame};
+use server::{self, ClientEvent, ClientId, Event, Runtime, ServerHandle, ServerOptions};
+
+/// How many turns ahead of the one in progress clients can send inputs for.
+const MAX_TURNS_AHEAD: u64 = 16;
+
+/// Announces the start of a turn to a `LockstepServer` and its clients.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurnInfo {
+    /// The turn counter, starting from 0.
+    pub number: u64,
+    /// How long after the start of the turn inputs are accepted for.
+    pub time_limit: Duration,
+}
+
+/// The inputs collected for one turn, broadcast to every client as one frame.
+///
+/// Clients of a `LockstepServer<M>` implement `ShellClient<M, Turn<M>>`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Turn<M> {
+    /// The turn counter, matching the `TurnInfo` the turn started with.
+    pub number: u64,
+    /// Each participating client's input, ordered by client.
+    pub inputs: Vec<(ClientId, M)>,
+}
+
+/// Trait implemented by a struct to define a turn-synchronous server, on which every client sees
+/// the same ordered set of inputs each turn.
+///
+/// Each turn, the server waits for one input from every client connected when the turn started,
+/// or until the turn's time limit runs out, then broadcasts every collected input together as a
+/// [Turn](struct.Turn.html).
+///
+/// Clients say which turn each input is for: the turn they last saw start, or the one they pass
+/// to `KeyAction::SendForTurn`. Inputs for a turn that's already over are dropped, and reported
+/// to the client's `on_delivery` as `Delivery::Rejected`, and those for a turn that hasn't started
+/// yet are held until it does, so every client's inputs land in the turn they were meant for.
+pub trait LockstepServer<M>
+where
+    M: Serialize + DeserializeOwned + Send + 'static + Clone,
+{
+    /// The local address to which the server will bind.
+    fn local_address(&self) -> String;
+
+    /// How long to wait for inputs each turn.
+    fn turn_time_limit(&self) -> Duration;
+
+    /// The input used for a client that sent none before the time limit. Defaults to `None`,
+    /// which leaves that client out of the turn.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use syncterm::server::ClientId;
+    /// # enum Move { Pass }
+    /// # struct App();
+    /// # impl App {
+    /// fn default_input(&self, _client: ClientId, _turn: u64) -> Option<Move> {
+    ///     Some(Move::Pass)
+    /// }
+    /// # }
+    /// ```
+    fn default_input(&self, _client: ClientId, _turn: u64) -> Option<M> {
+        None
+    }
+
+    /// Called at the start of each turn, before it is announced to clients.
+    fn on_turn_start(&self, _turn: &TurnInfo) {}
+
+    /// Called with each completed turn, before it is broadcast to clients.
+    fn on_turn(&self, _turn: &Turn<M>) {}
+
+    /// Returns what to report the server's events to and whether to collect statistics about it,
+    /// as `ShellServer::options` does. There's no `process_input`, so its latency isn't recorded.
+    fn options(&self) -> ServerOptions {
+        ServerOptions::new()
+    }
+}
+
+/// The "main" function for LockstepServers.
+///
+/// Binds a listener to the LockstepServer's local address, handles client connections, and runs
+/// turns for as long as any client is connected.
+///
+/// Returns once every client has gone and the server is shut down. Errors if the listener, or the
+/// server's metrics or admin socket, fail to bind.
+pub fn spawn_lockstep_and_listen<M, S>(server: S) -> Result<(), String>
+where
+    M: Serialize + DeserializeOwned + Send + 'static + Clone,
+    S: LockstepServer<M>,
+{
+    let runtime = server::start(&server.local_address(), server.options())?;
+    server::run(runtime, |runtime| run_turns(&server, runtime));
+    Ok(())
+}
+
+/// Like `spawn_lockstep_and_listen`, but runs the server in the background, returning a handle to
+/// it once it's listening.
+///
+/// Errors if the listener, or the server's metrics or admin socket, fail to bind.
+pub fn spawn_lockstep<M, S>(server: S) -> Result<ServerHandle, String>
+where
+    M: Serialize + DeserializeOwned + Send + 'static + Clone,
+    S: LockstepServer<M> + Send + 'static,
+{
+    let runtime = server::start(&server.local_address(), server.options())?;
+    Ok(ServerHandle::spawn(runtime, move |runtime| {
+        run_turns(&server, runtime)
+    }))
+}
+
+/// The server's main loop, which runs until every client has gone and the server is shut down.
+fn run_turns<M, S>(server: &S, runtime: Runtime<M, Turn<M>>)
+where
+    M: Serialize + DeserializeOwned + Send + 'static + Clone,
+    S: LockstepServer<M>,
+{
+    let Runtime {
+        events: stm_shl_rx,
+        senders: shl_stm_sxs,
+        observer,
+        ..
+    } = runtime;
+
+    let mut connected = BTreeSet::new();
+    let mut turns = Turns::new();
+    for number in 0.. {
+        // Don't run turns for nobody
+        while connected.is_empty() {
+            match stm_shl_rx.recv() {
+                Ok(Event::Client(client, ClientEvent::Connected)) => {
+                    connected.insert(client);
+                }
+                Ok(_) => {}
+                Err(_) => return,
+            }
+        }
+
+        let info = TurnInfo {
+            number,
+            time_limit: server.turn_time_limit(),
+        };
+        server.on_turn_start(&info);
+        turns.start(number, &connected);
+        server::broadcast(ServerFrame::TurnStart(info), &shl_stm_sxs);
+
+        // A time limit too long to represent never runs out
+        let deadline = Instant::now().checked_add(info.time_limit);
+        while !turns.is_complete() {
+            let event = match deadline {
+                Some(deadline) => {
+                    stm_shl_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => stm_shl_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let (client, seq, turn, input) = match event {
+                Ok(Event::Client(client, ClientEvent::Connected)) => {
+                    // Joins from the next turn on
+                    connected.insert(client);
+                    continue;
+                }
+                Ok(Event::Client(client, ClientEvent::Disconnected)) => {
+                    connected.remove(&client);
+                    turns.disconnect(client);
+                    continue;
+                }
+                // Inputs from clients that don't say which turn they're for are for this one
+                Ok(Event::Client(client, ClientEvent::Frame(ClientFrame::Input(seq, input)))) => {
+                    (client, seq, number, input)
+                }
+                Ok(Event::Client(
+                    client,
+                    ClientEvent::Frame(ClientFrame::TurnInput(seq, turn, input)),
+                )) => (client, seq, turn, input),
+                // Dropping the answering sender refuses the announcement
+                Ok(Event::Announce(..)) => continue,
+                // There's no synced state to send
+                Ok(Event::Client(_, ClientEvent::Resync)) => continue,
+                Ok(Event::Client(client, ClientEvent::Frame(ClientFrame::Request(id, _)))) => {
+                    observer.observe(&ServerEvent::Ignored {
+                        client,
+                        reason: "requests aren't supported in lockstep".to_owned(),
+                    });
+                    server::send_to(client, ServerFrame::Refused(id), &shl_stm_sxs);
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            let answer = match turns.input(client, turn, input) {
+                Ok(()) => ServerFrame::Ack(seq),
+                Err(reason) => {
+                    observer.observe(&ServerEvent::Ignored { client, reason });
+                    ServerFrame::Rejected(seq)
+                }
+            };
+            server::send_to(client, answer, &shl_stm_sxs);
+        }
+
+        let turn = turns.finish(|client| server.default_input(client, number));
+        server.on_turn(&turn);
+        let relayed = server::broadcast(ServerFrame::Broadcast(turn), &shl_stm_sxs);
+        observer.observe(&ServerEvent::Broadcast { clients: relayed });
+    }
+}
+
+/// The inputs collected for the turn in progress, and those sent early for later turns.
+struct Turns<M> {
+    number: u64,
+    waiting_on: BTreeSet<ClientId>,
+    inputs: BTreeMap<ClientId, M>,
+    early: BTreeMap<u64, BTreeMap<ClientId, M>>,
+}
+
+impl<M> Turns<M> {
+    fn new() -> Self {
+        Self {
+            number: 0,
+            waiting_on: BTreeSet::new(),
+            inputs: BTreeMap::new(),
+            early: BTreeMap::new(),
+        }
+    }
+
+    /// Starts the numbered turn, waiting on every client in `connected` that didn't send an input
+    /// for it early.
+    fn start(&mut self, number: u64, connected: &BTreeSet<ClientId>) {
+        let mut inputs = self.early.remove(&number).unwrap_or_default();
+        inputs.retain(|client, _| connected.contains(client));
+
+        self.number = number;
+        self.waiting_on = connected
+            .iter()
+            .filter(|client| !inputs.contains_key(client))
+            .cloned()
+            .collect();
+        self.inputs = inputs;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.waiting_on.is_empty()
+    }
+
+    /// Records a client's input for the numbered turn, holding on to it if that turn hasn't
+    /// started yet. Returns why the input was dropped, if it was.
+    fn input(&mut self, client: ClientId, turn: u64, input: M) -> Result<(), String> {
+        if turn < self.number {
+            return Err(format!(
+                "input for turn {} arrived in turn {}, after it ended",
+                turn, self.number
+            ));
+        }
+        if turn > self.number + MAX_TURNS_AHEAD {
+            return Err(format!(
+                "input for turn {} is too far ahead of turn {}",
+                turn, self.number
+            ));
+        }
+
+        if turn == self.number {
+            if self.waiting_on.remove(&client) {
+                self.inputs.insert(client, input);
+                return Ok(());
+            }
+            return Err(if self.inputs.contains_key(&client) {
+                format!("already sent an input for turn {}", turn)
+            } else {
+                format!("joined after turn {} started", turn)
+            });
+        }
+
+        match self.early.entry(turn).or_default().entry(client) {
+            Entry::Vacant(entry) => {
+                entry.insert(input);
+                Ok(())
+            }
+            Entry::Occupied(_) => Err(format!("already sent an input for turn {}", turn)),
+        }
+    }
+
+    /// Stops waiting on a client, and forgets any inputs it sent for later turns.
+    fn disconnect(&mut self, client: ClientId) {
+        self.waiting_on.remove(&client);
+        for inputs in self.early.values_mut() {
+            inputs.remove(&client);
+        }
+    }
+
+    /// Ends the turn, with `default_input` for each client that sent none.
+    fn finish<F>(&mut self, default_input: F) -> Turn<M>
+    where
+        F: Fn(ClientId) -> Option<M>,
+    {
+        for client in mem::take(&mut self.waiting_on) {
+            if let Some(input) = default_input(client) {
+                self.inputs.insert(client, input);
+            }
+        }
+
+        Turn {
+            number: self.number,
+            inputs: mem::take(&mut self.inputs).into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use admin::Control;
+    use observer::ServerObserver;
+    use server::ClientSender;
+
+    fn clients(ids: &[u64]) -> BTreeSet<ClientId> {
+        ids.iter().map(|&id| ClientId(id)).collect()
+    }
+
+    #[test]
+    fn completes_once_every_client_has_sent_an_input() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1, 2]));
+
+        assert_eq!(turns.input(ClientId(1), 0, "a"), Ok(()));
+        assert!(!turns.is_complete());
+        assert_eq!(turns.input(ClientId(2), 0, "b"), Ok(()));
+        assert!(turns.is_complete());
+
+        let turn = turns.finish(|_| None);
+        assert_eq!(turn.number, 0);
+        assert_eq!(turn.inputs, vec![(ClientId(1), "a"), (ClientId(2), "b")]);
+    }
+
+    #[test]
+    fn drops_inputs_for_turns_that_are_over() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1]));
+        turns.finish(|_| None);
+        turns.start(1, &clients(&[1]));
+
+        assert!(turns.input(ClientId(1), 0, "late").is_err());
+        assert!(!turns.is_complete());
+        assert!(turns.finish(|_| None).inputs.is_empty());
+    }
+
+    #[test]
+    fn holds_early_inputs_until_their_turn() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1, 2]));
+        assert_eq!(turns.input(ClientId(1), 0, "now"), Ok(()));
+        assert_eq!(turns.input(ClientId(1), 1, "next"), Ok(()));
+        assert_eq!(turns.finish(|_| None).inputs, vec![(ClientId(1), "now")]);
+
+        turns.start(1, &clients(&[1, 2]));
+        assert!(!turns.is_complete());
+        assert_eq!(turns.input(ClientId(2), 1, "other"), Ok(()));
+        assert!(turns.is_complete());
+        assert_eq!(
+            turns.finish(|_| None).inputs,
+            vec![(ClientId(1), "next"), (ClientId(2), "other")]
+        );
+    }
+
+    #[test]
+    fn rejects_a_second_input_for_the_same_turn() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1, 2]));
+
+        assert_eq!(turns.input(ClientId(1), 0, "a"), Ok(()));
+        assert!(turns.input(ClientId(1), 0, "b").is_err());
+        assert_eq!(turns.input(ClientId(1), 1, "c"), Ok(()));
+        assert!(turns.input(ClientId(1), 1, "d").is_err());
+    }
+
+    #[test]
+    fn rejects_inputs_too_far_ahead() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1]));
+
+        assert!(turns.input(ClientId(1), MAX_TURNS_AHEAD + 1, "a").is_err());
+        assert_eq!(turns.input(ClientId(1), MAX_TURNS_AHEAD, "b"), Ok(()));
+    }
+
+    #[test]
+    fn fills_in_default_inputs_and_forgets_disconnected_clients() {
+        let mut turns = Turns::new();
+        turns.start(0, &clients(&[1, 2, 3]));
+        assert_eq!(turns.input(ClientId(3), 1, "early"), Ok(()));
+        turns.disconnect(ClientId(3));
+        assert_eq!(turns.input(ClientId(1), 0, "a"), Ok(()));
+
+        let turn = turns.finish(|client| Some(if client == ClientId(2) { "pass" } else { "?" }));
+        assert_eq!(turn.inputs, vec![(ClientId(1), "a"), (ClientId(2), "pass")]);
+
+        turns.start(1, &clients(&[1, 2]));
+        assert!(turns.finish(|_| None).inputs.is_empty());
+    }
+
+    struct Game;
+
+    impl LockstepServer<String> for Game {
+        fn local_address(&self) -> String {
+            "127.0.0.1:0".to_owned()
+        }
+
+        fn turn_time_limit(&self) -> Duration {
+            Duration::from_secs(60)
+        }
+    }
+
+    #[test]
+    fn acknowledges_only_the_inputs_it_takes() {
+        let client = ClientId(1);
+        let observer: Arc<dyn ServerObserver> = Arc::new(|_: &ServerEvent| {});
+        let (sx, rx) = channel();
+        let mut senders = HashMap::new();
+        let queued = Arc::new(AtomicUsize::new(0));
+        senders.insert(
+            client,
+            ClientSender::new(client, sx, queued, observer.clone()),
+        );
+
+        let (events_sx, events) = channel();
+        let frames = vec![
+            ClientFrame::TurnInput(1, 0, "a".to_owned()),
+            // Turn 0 is over once the only client has sent its input
+            ClientFrame::TurnInput(2, 0, "late".to_owned()),
+            ClientFrame::TurnInput(3, 1, "b".to_owned()),
+        ];
+        events_sx
+            .send(Event::Client(client, ClientEvent::Connected))
+            .unwrap();
+        for frame in frames {
+            events_sx
+                .send(Event::Client(client, ClientEvent::Frame(frame)))
+                .unwrap();
+        }
+        drop(events_sx);
+
+        run_turns(
+            &Game,
+            Runtime {
+                events,
+                senders: Arc::new(Mutex::new(senders)),
+                observer,
+                recorder: None,
+                control: Arc::new(Control::default()),
+            },
+        );
+
+        let answers: Vec<_> = rx
+            .try_iter()
+            .filter_map(|frame| match frame {
+                ServerFrame::Ack(seq) => Some(format!("ack {}", seq)),
+                ServerFrame::Rejected(seq) => Some(format!("rejected {}", seq)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(answers, ["ack 1", "rejected 2", "ack 3"]);
+    }
+}
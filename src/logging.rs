@@ -0,0 +1,268 @@
+//! Logs a client's diagnostics to a file, since it can't print them while it owns the terminal.
+//!
+//! Once installed, the logger records syncterm's own events (connecting, losing and recovering
+//! the connection, frames that can't be read) under `syncterm::` targets, along with any records
+//! the app logs through the `log` crate's macros. Panics are logged with a backtrace, after the
+//! terminal is restored.
+//!
+//! `client::connect` and `multi::connect_all` install it if `ShellClient::log_options` returns
+//! some, which by default it does when the `SYNCTERM_LOG` environment variable is set.
+
+use std::backtrace::Backtrace;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+/// The environment variable naming the file to log to.
+pub const LOG_VAR: &str = "SYNCTERM_LOG";
+
+/// The environment variable setting the most detailed level to log, such as `debug`. Defaults to
+/// `info`.
+pub const LOG_LEVEL_VAR: &str = "SYNCTERM_LOG_LEVEL";
+
+/// Returned by `ShellClient::log_options` to log to a file, which is rotated once it grows past
+/// a size: `client.log` is renamed `client.log.1`, `client.log.1` becomes `client.log.2`, and so
+/// on, keeping up to `max_files` old logs.
+///
+/// # Examples
+/// ```
+/// # extern crate log;
+/// # extern crate syncterm;
+/// # use log::LevelFilter;
+/// # use syncterm::logging::LogOptions;
+/// # fn main() {
+/// let options = LogOptions::new("/tmp/chat.log")
+///     .level(LevelFilter::Debug)
+///     .max_size(1 << 20)
+///     .max_files(3);
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogOptions {
+    path: PathBuf,
+    level: LevelFilter,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl LogOptions {
+    /// Logs records at `info` and above to `path`, rotating it every 10MB and keeping 5 old logs.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            level: LevelFilter::Info,
+            max_size: 10 << 20,
+            max_files: 5,
+        }
+    }
+
+    /// Reads the file from `SYNCTERM_LOG` and the level from `SYNCTERM_LOG_LEVEL`. Returns `None`
+    /// if `SYNCTERM_LOG` isn't set.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var_os(LOG_VAR)?;
+        let options = Self::new(path);
+
+        Some(
+            match env::var(LOG_LEVEL_VAR).ok().and_then(|l| l.parse().ok()) {
+                Some(level) => options.level(level),
+                None => options,
+            },
+        )
+    }
+
+    /// Sets the most detailed level to log.
+    pub fn level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets the size in bytes past which the file is rotated.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets how many rotated files to keep. With none, the file is emptied instead.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+}
+
+/// Installs a logger writing to the file in `options`, and logs panics to it.
+///
+/// If another logger is already installed, such as the app's own or one from an earlier call,
+/// it's left in place to carry on receiving records, and panics aren't logged. Fails only if
+/// the file can't be opened.
+pub fn init(options: LogOptions) -> Result<(), String> {
+    let file = open(&options.path)
+        .map_err(|e| format!("Failed to open log file {:?}: {:?}", options.path, e))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let level = options.level;
+
+    let installed = log::set_boxed_logger(Box::new(FileLogger {
+        options,
+        file: Mutex::new((file, size)),
+    }));
+    if installed.is_err() {
+        warn!("A logger is already installed, so not logging to a file");
+        return Ok(());
+    }
+    log::set_max_level(level);
+
+    // Chained before the terminal's hook is installed, so that it runs after the terminal is
+    // restored
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        error!("{}\n{}", info, Backtrace::force_capture());
+        previous_hook(info);
+    }));
+
+    Ok(())
+}
+
+struct FileLogger {
+    options: LogOptions,
+    // The open file, and how much has been written to it
+    file: Mutex<(File, u64)>,
+}
+
+impl FileLogger {
+    /// Moves each rotated file along by one, dropping the oldest, and starts a new file.
+    fn rotate(&self, file: &mut File) -> io::Result<()> {
+        let path = &self.options.path;
+        if self.options.max_files == 0 {
+            *file = File::create(path)?;
+            return Ok(());
+        }
+
+        for n in (1..self.options.max_files).rev() {
+            let from = rotated(path, n);
+            if from.exists() {
+                fs::rename(from, rotated(path, n + 1))?;
+            }
+        }
+        fs::rename(path, rotated(path, 1))?;
+        *file = open(path)?;
+        Ok(())
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.options.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {:<5} {}: {}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        let mut guard = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let (ref mut file, ref mut size) = *guard;
+        if *size > 0
+            && *size + line.len() as u64 > self.options.max_size
+            && self.rotate(file).is_ok()
+        {
+            *size = 0;
+        }
+        if file.write_all(line.as_bytes()).is_ok() {
+            *size += line.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .flush();
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use log::Level;
+
+    use super::*;
+
+    fn log(logger: &FileLogger, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target("test")
+                .args(format_args!("{:<40}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn rotates_past_the_size_limit_keeping_only_records_at_its_level() {
+        let dir = env::temp_dir().join(format!("syncterm-logging-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("client.log");
+        // Room for one record per file
+        let options = LogOptions::new(&path)
+            .level(LevelFilter::Info)
+            .max_size(100)
+            .max_files(2);
+        let logger = FileLogger {
+            options,
+            file: Mutex::new((open(&path).unwrap(), 0)),
+        };
+
+        log(&logger, Level::Debug, "too detailed");
+        let filtered = fs::read_to_string(&path).unwrap();
+        for message in &["a", "b", "c", "d"] {
+            log(&logger, Level::Info, message);
+        }
+        log(&logger, Level::Trace, "too detailed");
+        let read = |n| fs::read_to_string(rotated(&path, n)).ok();
+        let (current, first, second, third) = (
+            fs::read_to_string(&path).unwrap(),
+            read(1),
+            read(2),
+            read(3),
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(filtered, "");
+        assert!(current.contains("INFO  test: d "), "{:?}", current);
+        assert_eq!(current.lines().count(), 1);
+        assert!(first.unwrap().contains("INFO  test: c "));
+        assert!(second.unwrap().contains("INFO  test: b "));
+        assert_eq!(third, None);
+    }
+}
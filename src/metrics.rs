@@ -0,0 +1,532 @@
+//! Counts what a server does, for `ServerHandle::stats` and for Prometheus to scrape.
+//!
+//! Metrics are collected from the same events a server's observer sees, so they cost nothing
+//! unless the server's `options` turn them on, with `ServerOptions::metrics`.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use observer::{ServerEvent, ServerObserver};
+use server::{ClientId, StreamSenders};
+
+/// How long to wait for a scrape's request to arrive, before giving up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The upper bounds of the `process_input` latency histogram's buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Passed to `ServerOptions::metrics` to collect statistics about a server.
+///
+/// # Examples
+/// ```
+/// # use syncterm::metrics::Metrics;
+/// // Serves the statistics at http://127.0.0.1:9100/metrics
+/// let metrics = Metrics::new().serve("127.0.0.1:9100");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    address: Option<String>,
+}
+
+impl Metrics {
+    /// Collects statistics, for `ServerHandle::stats`, without serving them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also serves the statistics over HTTP on `address`, at `/metrics`, in Prometheus' text
+    /// format.
+    pub fn serve<A: Into<String>>(mut self, address: A) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+}
+
+/// A snapshot of a server's statistics, since it started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    /// Client connections accepted
+    pub connections: u64,
+    /// Clients connected now
+    pub connected: u64,
+    /// Inputs and requests received
+    pub inputs: u64,
+    /// Responses, state updates and turns broadcast to every client
+    pub broadcasts: u64,
+    /// Bytes of frames received from clients
+    pub bytes_in: u64,
+    /// Bytes of frames written to clients
+    pub bytes_out: u64,
+    /// Lines from clients that weren't frames the server understands
+    pub decode_errors: u64,
+    /// Clients whose connections failed, rather than being closed
+    pub dropped_clients: u64,
+    /// How long `process_input` took
+    pub latency: LatencyHistogram,
+    /// How many frames are waiting to be written to each connected client
+    pub queue_depths: Vec<(ClientId, usize)>,
+}
+
+/// How long a server took to process its inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    /// The number of inputs that took at most each bucket's upper bound, in seconds
+    pub buckets: Vec<(f64, u64)>,
+    /// The number of inputs processed
+    pub count: u64,
+    /// The total time spent processing them
+    pub sum: Duration,
+}
+
+/// Counts a server's events, before passing them on to its own observer.
+pub(crate) struct Recorder {
+    observer: Arc<dyn ServerObserver>,
+    address: Option<String>,
+    connections: AtomicU64,
+    disconnections: AtomicU64,
+    inputs: AtomicU64,
+    broadcasts: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    decode_errors: AtomicU64,
+    dropped_clients: AtomicU64,
+    // Not cumulative; made so when read
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_micros: AtomicU64,
+    queue_depths: OnceLock<QueueDepths>,
+}
+
+/// Reads how many frames are waiting to be written to each connected client.
+type QueueDepths = Box<dyn Fn() -> Vec<(ClientId, usize)> + Send + Sync>;
+
+/// Returns the observer to hand a server's events to: its own, or if it has `metrics`, a recorder
+/// wrapping it.
+pub(crate) fn record(
+    observer: Arc<dyn ServerObserver>,
+    metrics: Option<Metrics>,
+) -> (Arc<dyn ServerObserver>, Option<Arc<Recorder>>) {
+    let metrics = match metrics {
+        Some(metrics) => metrics,
+        None => return (observer, None),
+    };
+
+    let recorder = Arc::new(Recorder {
+        observer,
+        address: metrics.address,
+        connections: AtomicU64::new(0),
+        disconnections: AtomicU64::new(0),
+        inputs: AtomicU64::new(0),
+        broadcasts: AtomicU64::new(0),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        decode_errors: AtomicU64::new(0),
+        dropped_clients: AtomicU64::new(0),
+        latency_buckets: Default::default(),
+        latency_micros: AtomicU64::new(0),
+        queue_depths: OnceLock::new(),
+    });
+    (recorder.clone(), Some(recorder))
+}
+
+impl Recorder {
+    /// Starts reading queue depths from the server's connected clients, and serving the
+    /// statistics if the server asked to.
+    ///
+    /// Errors if the metrics address can't be bound.
+    pub fn start<R>(self: &Arc<Self>, shl_stm_sxs: &StreamSenders<R>) -> Result<(), String>
+    where
+        R: Send + 'static,
+    {
+        let sxs = shl_stm_sxs.clone();
+        let _ = self.queue_depths.set(Box::new(move || {
+            let guard = sxs.lock().expect("Poisoned map of outgoing sxs");
+            let mut depths: Vec<_> = guard
+                .iter()
+                .map(|(client, shl_stm_sx)| (*client, shl_stm_sx.queued()))
+                .collect();
+            depths.sort();
+            depths
+        }));
+
+        if let Some(ref address) = self.address {
+            let listener = TcpListener::bind(address)
+                .map_err(|e| format!("Failed to bind metrics to {:?}: {:?}", address, e))?;
+            let recorder = self.clone();
+            thread::spawn(move || {
+                // Each on a thread of its own, so that a slow scraper doesn't hold up the rest
+                for stream in listener.incoming().flatten() {
+                    let recorder = recorder.clone();
+                    thread::spawn(move || recorder.respond(stream));
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let mut cumulative = 0;
+        let mut buckets = Vec::with_capacity(LATENCY_BUCKETS.len() + 1);
+        for (bound, count) in LATENCY_BUCKETS
+            .iter()
+            .chain(Some(&f64::INFINITY))
+            .zip(&self.latency_buckets)
+        {
+            cumulative += load(count);
+            buckets.push((*bound, cumulative));
+        }
+
+        let connections = load(&self.connections);
+        ServerStats {
+            connections,
+            connected: connections.saturating_sub(load(&self.disconnections)),
+            inputs: load(&self.inputs),
+            broadcasts: load(&self.broadcasts),
+            bytes_in: load(&self.bytes_in),
+            bytes_out: load(&self.bytes_out),
+            decode_errors: load(&self.decode_errors),
+            dropped_clients: load(&self.dropped_clients),
+            latency: LatencyHistogram {
+                buckets,
+                count: cumulative,
+                sum: Duration::from_micros(load(&self.latency_micros)),
+            },
+            queue_depths: self.queue_depths.get().map(|f| f()).unwrap_or_default(),
+        }
+    }
+
+    /// Answers an HTTP request for `GET /metrics` with the statistics, and any other with a 404.
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+            line.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) if target.split('?').next() == Some("/metrics") => (
+                "200 OK",
+                "text/plain; version=0.0.4",
+                prometheus(&self.stats()),
+            ),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+    }
+}
+
+impl ServerObserver for Recorder {
+    fn observe(&self, event: &ServerEvent) {
+        let add = |counter: &AtomicU64, n: u64| {
+            counter.fetch_add(n, Ordering::Relaxed);
+        };
+
+        match *event {
+            ServerEvent::Connected { .. } => add(&self.connections, 1),
+            ServerEvent::Disconnected { failed, .. } => {
+                add(&self.disconnections, 1);
+                if failed {
+                    add(&self.dropped_clients, 1)
+                }
+            }
+            ServerEvent::InputReceived { bytes, .. } => {
+                add(&self.inputs, 1);
+                add(&self.bytes_in, bytes as u64)
+            }
+            ServerEvent::InputProcessed { elapsed, .. } => {
+                let secs = elapsed.as_secs_f64();
+                let bucket = LATENCY_BUCKETS
+                    .iter()
+                    .position(|bound| secs <= *bound)
+                    .unwrap_or(LATENCY_BUCKETS.len());
+                add(&self.latency_buckets[bucket], 1);
+                add(&self.latency_micros, elapsed.as_micros() as u64)
+            }
+            ServerEvent::Broadcast { .. } => add(&self.broadcasts, 1),
+            ServerEvent::FrameSent { bytes, .. } => add(&self.bytes_out, bytes as u64),
+            ServerEvent::DecodeError { .. } => add(&self.decode_errors, 1),
+            _ => {}
+        }
+
+        self.observer.observe(event);
+    }
+}
+
+/// Formats statistics in Prometheus' text exposition format.
+fn prometheus(stats: &ServerStats) -> String {
+    let mut out = String::new();
+    let counters = [
+        (
+            "connections_total",
+            "Client connections accepted.",
+            stats.connections,
+        ),
+        (
+            "inputs_total",
+            "Inputs and requests received.",
+            stats.inputs,
+        ),
+        (
+            "broadcasts_total",
+            "Responses, state updates and turns broadcast to every client.",
+            stats.broadcasts,
+        ),
+        (
+            "received_bytes_total",
+            "Bytes received from clients.",
+            stats.bytes_in,
+        ),
+        (
+            "sent_bytes_total",
+            "Bytes written to clients.",
+            stats.bytes_out,
+        ),
+        (
+            "decode_errors_total",
+            "Undecodable lines from clients.",
+            stats.decode_errors,
+        ),
+        (
+            "dropped_clients_total",
+            "Client connections that failed.",
+            stats.dropped_clients,
+        ),
+    ];
+    for &(name, help, value) in &counters {
+        let _ = writeln!(out, "# HELP syncterm_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE syncterm_{} counter", name);
+        let _ = writeln!(out, "syncterm_{} {}", name, value);
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP syncterm_connected_clients Clients connected now."
+    );
+    let _ = writeln!(out, "# TYPE syncterm_connected_clients gauge");
+    let _ = writeln!(out, "syncterm_connected_clients {}", stats.connected);
+
+    let _ = writeln!(
+        out,
+        "# HELP syncterm_process_input_seconds Time taken to process an input."
+    );
+    let _ = writeln!(out, "# TYPE syncterm_process_input_seconds histogram");
+    for &(bound, count) in &stats.latency.buckets {
+        let le = if bound.is_infinite() {
+            "+Inf".to_owned()
+        } else {
+            bound.to_string()
+        };
+        let _ = writeln!(
+            out,
+            "syncterm_process_input_seconds_bucket{{le=\"{}\"}} {}",
+            le, count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "syncterm_process_input_seconds_sum {}",
+        stats.latency.sum.as_secs_f64()
+    );
+    let _ = writeln!(
+        out,
+        "syncterm_process_input_seconds_count {}",
+        stats.latency.count
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP syncterm_client_queue_depth Frames waiting to be written to a client."
+    );
+    let _ = writeln!(out, "# TYPE syncterm_client_queue_depth gauge");
+    for &(client, depth) in &stats.queue_depths {
+        let _ = writeln!(
+            out,
+            "syncterm_client_queue_depth{{client=\"{}\"}} {}",
+            client.0, depth
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    fn recorder() -> Arc<Recorder> {
+        let (_, recorder) = record(Arc::new(|_: &ServerEvent| {}), Some(Metrics::new()));
+        recorder.unwrap()
+    }
+
+    #[test]
+    fn counts_events() {
+        let recorder = recorder();
+        let client = ClientId(0);
+        let events = vec![
+            ServerEvent::Connected {
+                client,
+                addr: "127.0.0.1:5000".parse().unwrap(),
+            },
+            ServerEvent::InputReceived { client, bytes: 10 },
+            ServerEvent::InputProcessed {
+                client,
+                elapsed: Duration::from_millis(2),
+            },
+            ServerEvent::Broadcast { clients: 1 },
+            ServerEvent::FrameSent { client, bytes: 20 },
+            ServerEvent::StreamError {
+                client,
+                error: "Failed to read".to_owned(),
+            },
+            ServerEvent::Disconnected {
+                client,
+                failed: true,
+            },
+        ];
+        for event in &events {
+            recorder.observe(event);
+        }
+
+        let stats = recorder.stats();
+        assert_eq!(
+            (
+                stats.connections,
+                stats.connected,
+                stats.inputs,
+                stats.broadcasts
+            ),
+            (1, 0, 1, 1)
+        );
+        assert_eq!((stats.bytes_in, stats.bytes_out), (10, 20));
+        // Counted once, when the client disconnects, however many errors led up to it
+        assert_eq!(stats.dropped_clients, 1);
+
+        assert_eq!(stats.latency.count, 1);
+        assert_eq!(stats.latency.sum, Duration::from_millis(2));
+        assert_eq!(stats.latency.buckets[2], (0.001, 0));
+        assert_eq!(stats.latency.buckets[3], (0.005, 1));
+        assert_eq!(stats.latency.buckets.last(), Some(&(f64::INFINITY, 1)));
+    }
+
+    #[test]
+    fn doesnt_count_clients_that_close_their_connections_as_dropped() {
+        let recorder = recorder();
+        recorder.observe(&ServerEvent::Disconnected {
+            client: ClientId(0),
+            failed: false,
+        });
+
+        assert_eq!(recorder.stats().dropped_clients, 0);
+    }
+
+    #[test]
+    fn formats_statistics_for_prometheus() {
+        let stats = ServerStats {
+            connections: 3,
+            connected: 2,
+            inputs: 0,
+            broadcasts: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            decode_errors: 0,
+            dropped_clients: 1,
+            latency: LatencyHistogram {
+                buckets: vec![(0.5, 1), (f64::INFINITY, 2)],
+                count: 2,
+                sum: Duration::from_millis(1500),
+            },
+            queue_depths: vec![(ClientId(4), 7)],
+        };
+        let out = prometheus(&stats);
+
+        for line in &[
+            "# TYPE syncterm_connections_total counter",
+            "syncterm_connections_total 3",
+            "syncterm_dropped_clients_total 1",
+            "# TYPE syncterm_connected_clients gauge",
+            "syncterm_connected_clients 2",
+            "syncterm_process_input_seconds_bucket{le=\"0.5\"} 1",
+            "syncterm_process_input_seconds_bucket{le=\"+Inf\"} 2",
+            "syncterm_process_input_seconds_sum 1.5",
+            "syncterm_process_input_seconds_count 2",
+            "syncterm_client_queue_depth{client=\"4\"} 7",
+        ] {
+            assert!(
+                out.lines().any(|l| l == *line),
+                "No {:?} in:\n{}",
+                line,
+                out
+            );
+        }
+
+        // Every sample follows its metric's TYPE
+        let mut described = Vec::new();
+        for line in out.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                described.push(rest.split(' ').next().unwrap().to_owned());
+            } else if !line.starts_with('#') {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert!(
+                    described
+                        .iter()
+                        .any(|metric| name.starts_with(metric.as_str())),
+                    "{} isn't described",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn serves_only_the_metrics_path() {
+        let recorder = recorder();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let scrape = |request: &str| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(request.as_bytes()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            recorder.respond(stream).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        for path in &["/metrics", "/metrics?name=syncterm"] {
+            let response = scrape(&format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path));
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.contains("# TYPE syncterm_"), "{}", response);
+        }
+        for request in &["GET / HTTP/1.1", "POST /metrics HTTP/1.1", "nonsense"] {
+            let response = scrape(&format!("{}\r\n\r\n", request));
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{}",
+                response
+            );
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! Runs one client connected to several servers at once, such as a dashboard watching a server
+//! per repository.
+
+use std::time::Duration;
+
+use client::{
+    self, Event, Key, KeyAction, MouseEvent, RequestError, RequestId, ShellClient, TerminalOptions,
+};
+use interpolation::Interpolation;
+use lockstep::TurnInfo;
+use logging::{self, LogOptions};
+use outbox::{Delivery, MessageId, Outbox};
+use serde::{Serialize, de::DeserializeOwned};
+use session::{Frontend, Next, Routed};
+use shell_connection::ShellConnection;
+use stats::NetStats;
+use terminal::TerminalSession;
+
+/// Returned by `MultiClient::on_key` (and `on_event`) to specify an action to be triggered after
+/// a key is pressed.
+#[derive(Debug, Clone)]
+pub enum MultiAction<M: Serialize> {
+    DoNothing,
+    /// Exits from synced terminal
+    Exit,
+    /// Sends a user's input to the named server
+    SendTo(String, M),
+    /// Carries out an action on the connection to the named server, as `client::connect` does
+    /// for a `ShellClient`, such as sending a `KeyAction::Request`
+    To(String, KeyAction<M>),
+}
+
+/// Implemented by clients connected to several servers, each given a name by the client.
+pub trait MultiClient<M, R>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send,
+{
+    /// Returns the name and URL of each server to connect to.
+    fn servers(&self) -> Vec<(String, String)>;
+
+    /// Given a key press, defines actions to take.
+    fn on_key(&mut self, key: Key) -> MultiAction<M>;
+
+    /// Returns how `connect_all` should set up the terminal, as `ShellClient::terminal_options`
+    /// does.
+    fn terminal_options(&self) -> TerminalOptions {
+        TerminalOptions::default()
+    }
+
+    /// Returns where `connect_all` should log, as `ShellClient::log_options` does.
+    fn log_options(&self) -> Option<LogOptions> {
+        LogOptions::from_env()
+    }
+
+    /// Whether to enable mouse reporting in the terminal, so that `on_mouse` receives clicks and
+    /// scrolling. Defaults to `false`.
+    fn capture_mouse(&self) -> bool {
+        false
+    }
+
+    /// Given a mouse event, defines actions to take. Only called if `capture_mouse` is `true`.
+    fn on_mouse(&mut self, _mouse: MouseEvent) -> MultiAction<M> {
+        MultiAction::DoNothing
+    }
+
+    /// Given any input event, defines actions to take. Defaults to passing key presses to
+    /// `on_key` and mouse events to `on_mouse`, and ignoring anything else.
+    fn on_event(&mut self, event: Event) -> MultiAction<M> {
+        match event {
+            Event::Key(key) => self.on_key(key),
+            Event::Mouse(mouse) => self.on_mouse(mouse),
+            Event::Unsupported(_) => MultiAction::DoNothing,
+        }
+    }
+
+    /// In line mode, given a line of stdin, defines actions to take, as `ShellClient::on_line`
+    /// does.
+    fn on_line(&mut self, _line: &str) -> Option<MultiAction<M>> {
+        None
+    }
+
+    /// In line mode, returns the line to print for a response from the named server, if any.
+    /// Defaults to `None`, which prints nothing.
+    fn response_line(&self, _server: &str, _response: &R) -> Option<String> {
+        None
+    }
+
+    /// When a message is received from the named server, defines any actions to take.
+    fn receive_response(&mut self, server: &str, server_response: R);
+
+    /// When the reply to a request sent to the named server arrives, or the request fails,
+    /// defines any actions to take.
+    ///
+    /// Defaults to passing successful replies on to `receive_response`.
+    fn receive_reply(&mut self, server: &str, _id: RequestId, reply: Result<R, RequestError>) {
+        if let Ok(server_response) = reply {
+            self.receive_response(server, server_response);
+        }
+    }
+
+    /// When the named synced server's state changes, receives the client's up-to-date copy of it,
+    /// as `ShellClient::on_state` does.
+    fn on_state(&mut self, _server: &str, _state: &R) {}
+
+    /// Applies one of the client's own inputs to its copy of the named synced server's state,
+    /// ahead of the server doing so, as `ShellClient::predict` does. Defaults to predicting
+    /// nothing.
+    fn predict(&self, _server: &str, _state: &mut R, _input: &M) -> bool {
+        false
+    }
+
+    /// Returns how to smooth out the named synced server's states passed to `on_state`, as
+    /// `ShellClient::interpolation` does. Defaults to `None`.
+    fn interpolation(&self, _server: &str) -> Option<Interpolation<R>> {
+        None
+    }
+
+    /// Returns how to hold on to inputs for the named server while reconnecting to it, as
+    /// `ShellClient::outbox` does. Servers with an outbox should each persist to a file of their
+    /// own. Defaults to `None`, which gives up on the server once its connection is lost.
+    fn outbox(&self, _server: &str) -> Option<Outbox> {
+        None
+    }
+
+    /// When an input sent through the named server's outbox is queued, sent, acknowledged or
+    /// dropped, defines any actions to take.
+    fn on_delivery(&mut self, _server: &str, _id: MessageId, _delivery: Delivery) {}
+
+    /// When the connection to the named server is lost or recovers, defines any actions to take.
+    /// Only called for servers with an outbox.
+    fn on_connection(&mut self, _server: &str, _connected: bool) {}
+
+    /// When the named lockstep server starts a new turn, defines any actions to take, as
+    /// `ShellClient::on_turn_start` does.
+    fn on_turn_start(&mut self, _server: &str, _turn: TurnInfo) {}
+
+    /// When the connection to the named server is lost for good, defines any actions to take. The
+    /// client keeps running while any of its servers are connected.
+    fn on_disconnect(&mut self, _server: &str) {}
+
+    /// When an action is addressed to a server that isn't connected, either because it isn't one
+    /// of `servers` or because its connection was lost for good, defines any actions to take.
+    /// The action itself is dropped.
+    fn on_unreachable(&mut self, _server: &str) {}
+
+    /// Returns how often to tick and redraw the client UI between events, as
+    /// `ShellClient::frame_interval` does.
+    fn frame_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Returns the most times per second to redraw the client UI, as
+    /// `ShellClient::max_redraw_rate` does.
+    fn max_redraw_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Returns the key that shows or hides a debug overlay with the statistics of the connection
+    /// to the named server, as `ShellClient::debug_overlay_key` does. Servers should each have a
+    /// key of their own; showing one server's overlay hides any other's. Defaults to `None`.
+    fn debug_overlay_key(&self, _server: &str) -> Option<Key> {
+        None
+    }
+
+    /// Whether to collect the statistics of the connection to the named server, for the client's
+    /// own use, through `on_stats`. Defaults to `false`.
+    fn collect_stats(&self, _server: &str) -> bool {
+        false
+    }
+
+    /// Receives the network statistics of the connection to the named server, once a second, if
+    /// `collect_stats` is `true` for it.
+    fn on_stats(&mut self, _server: &str, _stats: &NetStats) {}
+
+    /// Called when the screen has been cleared from under the client UI, as
+    /// `ShellClient::on_invalidate` is.
+    fn on_invalidate(&mut self) {}
+
+    /// Called once per frame interval, before the UI is redrawn, with the time since the previous
+    /// tick.
+    fn on_tick(&mut self, _dt: Duration) {}
+
+    /// When the terminal window changes size, receives its new size in columns and rows. Also
+    /// called once with the initial size, before `first_draw`.
+    fn on_resize(&mut self, _cols: u16, _rows: u16) {}
+
+    /// Does any work to initialize the client UI.
+    fn first_draw(&mut self);
+
+    /// Updates the client UI, after every event and every frame interval.
+    fn draw(&mut self);
+
+    /// Does any work to tear-down the client UI.
+    fn last_draw(&mut self);
+}
+
+/// The "main" function for MultiClients.
+///
+/// Connects to every one of the client's servers, then captures stdin and runs the same update
+/// loop as `client::connect`, with a connection to each server that works as a `ShellClient`'s
+/// does: requests, outboxes and the rest. Each action is carried out on the connection to the
+/// server it's addressed to, and everything a server sends is tagged with its name. Also runs in
+/// line mode when `client::connect` would.
+///
+/// Returns an error only if the client has no servers or any of them fails to connect, its log
+/// file can't be opened, or the terminal can't be set up as asked for by its `terminal_options`.
+pub fn connect_all<C, M, R>(client: C) -> Result<(), String>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: MultiClient<M, R>,
+{
+    let servers = client.servers();
+    if servers.is_empty() {
+        return Err("no servers".to_owned());
+    }
+
+    if let Some(options) = client.log_options() {
+        logging::init(options)?;
+    }
+
+    let mut connections = Vec::new();
+    for (name, url) in &servers {
+        let connection = ShellConnection::connect(url).map_err(|e| {
+            error!("Failed to connect to {} at {}: {:?}", name, url, e);
+            format!("Failed to connect to server {}: {:?}", name, e)
+        })?;
+        info!("Connected to {} at {}", name, url);
+        connections.push(connection);
+    }
+
+    if client::is_line_mode() {
+        info!("Not attached to a terminal, so running in line mode");
+        client::run_stdin(connections, Multi::new(client, servers), true);
+        return Ok(());
+    }
+
+    let mut terminal_options = client.terminal_options();
+    terminal_options.mouse |= client.capture_mouse();
+    let terminal_session = TerminalSession::enter(terminal_options)
+        .map_err(|e| format!("Failed to set up terminal: {:?}", e))?;
+
+    client::run_stdin(connections, Multi::new(client, servers), false);
+
+    drop(terminal_session);
+    Ok(())
+}
+
+/// A MultiClient, with a session for each of its servers, in the order `servers` returned them.
+struct Multi<C> {
+    client: C,
+    servers: Vec<(String, String)>,
+    connected: Vec<bool>,
+}
+
+impl<C> Multi<C> {
+    fn new(client: C, servers: Vec<(String, String)>) -> Self {
+        let connected = vec![true; servers.len()];
+        Self {
+            client,
+            servers,
+            connected,
+        }
+    }
+
+    /// Returns the session an action is addressed to, or if its server isn't connected, tells
+    /// the client so and drops the action.
+    fn route<M, R>(&mut self, action: MultiAction<M>) -> Routed<M>
+    where
+        M: Serialize,
+        R: DeserializeOwned + Send,
+        C: MultiClient<M, R>,
+    {
+        let (name, action) = match action {
+            MultiAction::DoNothing => return Routed::Next(Next::Redraw),
+            MultiAction::Exit => return Routed::Next(Next::Exit),
+            MultiAction::SendTo(name, msg) => (name, KeyAction::SendMessage(msg)),
+            MultiAction::To(name, action) => (name, action),
+        };
+
+        match self.servers.iter().position(|server| server.0 == name) {
+            Some(index) if self.connected[index] => Routed::Session(index, action),
+            _ => {
+                warn!("Dropped an action for {}, which isn't connected", name);
+                self.client.on_unreachable(&name);
+                Routed::Next(Next::Redraw)
+            }
+        }
+    }
+}
+
+impl<C, M, R> Frontend<M, R> for Multi<C>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send,
+    C: MultiClient<M, R>,
+{
+    fn with_session<T, F>(&mut self, session: usize, f: F) -> T
+    where
+        F: FnOnce(&mut dyn ShellClient<M, R>) -> T,
+    {
+        let (ref name, ref url) = self.servers[session];
+        f(&mut Named {
+            client: &mut self.client,
+            name,
+            url,
+        })
+    }
+
+    fn on_event(&mut self, event: Event) -> Routed<M> {
+        let action = self.client.on_event(event);
+        self.route(action)
+    }
+
+    fn on_line(&mut self, line: &str) -> Option<Routed<M>> {
+        let action = self.client.on_line(line);
+        action.map(|action| self.route(action))
+    }
+
+    fn on_closed(&mut self, session: usize) {
+        self.connected[session] = false;
+        self.client.on_disconnect(&self.servers[session].0);
+    }
+
+    fn on_invalidate(&mut self) {
+        self.client.on_invalidate();
+    }
+
+    fn on_resize(&mut self, cols: u16, rows: u16) {
+        self.client.on_resize(cols, rows);
+    }
+
+    fn on_tick(&mut self, dt: Duration) {
+        self.client.on_tick(dt);
+    }
+
+    fn frame_interval(&self) -> Option<Duration> {
+        self.client.frame_interval()
+    }
+
+    fn max_redraw_rate(&self) -> Option<u32> {
+        self.client.max_redraw_rate()
+    }
+
+    fn first_draw(&mut self) {
+        self.client.first_draw();
+    }
+
+    fn draw(&mut self) {
+        self.client.draw();
+    }
+
+    fn last_draw(&mut self) {
+        self.client.last_draw();
+    }
+}
+
+/// A MultiClient as the session for one of its servers sees it: a ShellClient of that server
+/// alone, tagging everything it hands over with the server's name.
+struct Named<'a, C: 'a> {
+    client: &'a mut C,
+    name: &'a str,
+    url: &'a str,
+}
+
+impl<'a, C, M, R> ShellClient<M, R> for Named<'a, C>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send,
+    C: MultiClient<M, R>,
+{
+    fn server_url(&self) -> String {
+        self.url.to_owned()
+    }
+
+    // Input events go to the MultiClient itself, which addresses its actions to a server
+    fn on_key(&mut self, _key: Key) -> KeyAction<M> {
+        KeyAction::DoNothing
+    }
+
+    fn receive_response(&mut self, server_response: R) {
+        self.client.receive_response(self.name, server_response);
+    }
+
+    fn receive_reply(&mut self, id: RequestId, reply: Result<R, RequestError>) {
+        self.client.receive_reply(self.name, id, reply);
+    }
+
+    fn on_state(&mut self, state: &R) {
+        self.client.on_state(self.name, state);
+    }
+
+    fn predict(&self, state: &mut R, input: &M) -> bool {
+        self.client.predict(self.name, state, input)
+    }
+
+    fn interpolation(&self) -> Option<Interpolation<R>> {
+        self.client.interpolation(self.name)
+    }
+
+    fn response_line(&self, response: &R) -> Option<String> {
+        self.client.response_line(self.name, response)
+    }
+
+    fn outbox(&self) -> Option<Outbox> {
+        self.client.outbox(self.name)
+    }
+
+    fn on_delivery(&mut self, id: MessageId, delivery: Delivery) {
+        self.client.on_delivery(self.name, id, delivery);
+    }
+
+    fn on_connection(&mut self, connected: bool) {
+        self.client.on_connection(self.name, connected);
+    }
+
+    fn on_turn_start(&mut self, turn: TurnInfo) {
+        self.client.on_turn_start(self.name, turn);
+    }
+
+    fn debug_overlay_key(&self) -> Option<Key> {
+        self.client.debug_overlay_key(self.name)
+    }
+
+    fn collect_stats(&self) -> bool {
+        self.client.collect_stats(self.name)
+    }
+
+    fn on_stats(&mut self, stats: &NetStats) {
+        self.client.on_stats(self.name, stats);
+    }
+
+    fn first_draw(&mut self) {}
+
+    fn draw(&mut self) {}
+
+    fn last_draw(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interpolation::Lerp;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+    struct Total(u32);
+
+    impl Lerp for Total {
+        fn lerp(&self, other: &Total, t: f64) -> Total {
+            if t < 0.5 {
+                *self
+            } else {
+                *other
+            }
+        }
+    }
+
+    /// Records which server each hook is called for. Inputs add themselves to the state.
+    #[derive(Default)]
+    struct Counters {
+        servers: Vec<(String, String)>,
+        states: Vec<(String, Total)>,
+        turns: Vec<(String, u64)>,
+        stats: Vec<String>,
+    }
+
+    impl MultiClient<u32, Total> for Counters {
+        fn servers(&self) -> Vec<(String, String)> {
+            self.servers.clone()
+        }
+
+        fn on_key(&mut self, _key: Key) -> MultiAction<u32> {
+            MultiAction::DoNothing
+        }
+
+        fn receive_response(&mut self, _server: &str, _server_response: Total) {}
+
+        fn on_state(&mut self, server: &str, state: &Total) {
+            self.states.push((server.to_owned(), *state));
+        }
+
+        fn predict(&self, server: &str, state: &mut Total, input: &u32) -> bool {
+            state.0 += input;
+            server == "a"
+        }
+
+        fn interpolation(&self, server: &str) -> Option<Interpolation<Total>> {
+            if server == "a" {
+                Some(Interpolation::new(Duration::from_millis(100)))
+            } else {
+                None
+            }
+        }
+
+        fn on_turn_start(&mut self, server: &str, turn: TurnInfo) {
+            self.turns.push((server.to_owned(), turn.number));
+        }
+
+        fn debug_overlay_key(&self, server: &str) -> Option<Key> {
+            server.chars().next().map(Key::Char)
+        }
+
+        fn collect_stats(&self, server: &str) -> bool {
+            server == "b"
+        }
+
+        fn on_stats(&mut self, server: &str, _stats: &NetStats) {
+            self.stats.push(server.to_owned());
+        }
+
+        fn first_draw(&mut self) {}
+
+        fn draw(&mut self) {}
+
+        fn last_draw(&mut self) {}
+    }
+
+    #[test]
+    fn refuses_to_run_without_servers() {
+        assert_eq!(
+            connect_all(Counters::default()),
+            Err("no servers".to_owned())
+        );
+    }
+
+    #[test]
+    fn hands_each_server_its_own_hooks() {
+        let servers = vec![
+            ("a".to_owned(), "127.0.0.1:1".to_owned()),
+            ("b".to_owned(), "127.0.0.1:2".to_owned()),
+        ];
+        let mut multi = Multi::new(Counters::default(), servers);
+
+        for session in 0..2 {
+            multi.with_session(session, |client| {
+                let mut state = Total(1);
+                let predicted = client.predict(&mut state, &2);
+                client.on_state(&state);
+                client.on_turn_start(TurnInfo {
+                    number: session as u64,
+                    time_limit: Duration::from_secs(1),
+                });
+                client.on_stats(&NetStats::default());
+                assert_eq!(predicted, session == 0);
+                assert_eq!(client.interpolation().is_some(), session == 0);
+                assert_eq!(client.collect_stats(), session == 1);
+            });
+        }
+
+        assert_eq!(
+            multi.with_session(0, |c| c.debug_overlay_key()),
+            Some(Key::Char('a'))
+        );
+        assert_eq!(
+            multi.client.states,
+            vec![("a".to_owned(), Total(3)), ("b".to_owned(), Total(3))]
+        );
+        assert_eq!(
+            multi.client.turns,
+            vec![("a".to_owned(), 0), ("b".to_owned(), 1)]
+        );
+        assert_eq!(multi.client.stats, vec!["a".to_owned(), "b".to_owned()]);
+    }
+}
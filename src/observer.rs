@@ -0,0 +1,149 @@
+//! Reports what a server is doing, as typed events, so that operators can filter, ship or silence
+//! them.
+//!
+//! Every kind of server hands its events to the observer in the `ServerOptions` returned by its
+//! `options` method, which by default logs them through the `log` crate under the
+//! `syncterm::observer` target.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use server::ClientId;
+
+/// How many frames can be waiting to be written to a client before it's reported as slow.
+pub const SLOW_CLIENT_QUEUE: usize = 256;
+
+/// Something that happened on a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client connected from `addr`
+    Connected { client: ClientId, addr: SocketAddr },
+    /// A connection from a banned address, or made while shutting down, was refused
+    Rejected { addr: SocketAddr },
+    /// A client's connection closed, or if `failed`, broke: reading from or writing to it failed
+    Disconnected { client: ClientId, failed: bool },
+    /// An input or request arrived from a client
+    InputReceived { client: ClientId, bytes: usize },
+    /// The server took `elapsed` to process an input or request from a client
+    InputProcessed { client: ClientId, elapsed: Duration },
+    /// A response was broadcast to this many clients
+    Broadcast { clients: usize },
+    /// A frame was written to a client
+    FrameSent { client: ClientId, bytes: usize },
+    /// The reply to a client's request was sent, or dropped because the client had left
+    Replied { client: ClientId, delivered: bool },
+    /// A client sent a line that isn't a frame the server understands
+    DecodeError {
+        client: ClientId,
+        line: String,
+        error: String,
+    },
+    /// This many frames are waiting to be written to a client, which isn't keeping up
+    SlowClient { client: ClientId, queued: usize },
+    /// The server panicked processing an input from a client, which was dropped
+    InputPanicked { client: ClientId },
+    /// A client's frame was ignored, because the server doesn't accept it
+    Ignored { client: ClientId, reason: String },
+    /// Reading from or writing to a client failed
+    StreamError { client: ClientId, error: String },
+    /// Accepting a connection failed, such as when the server ran out of file descriptors
+    AcceptError { error: String },
+    /// An operator sent a command over the admin socket
+    AdminCommand { command: String },
+}
+
+/// Receives a server's events, from whichever thread they happen on.
+///
+/// Implemented for closures taking a `&ServerEvent`.
+///
+/// # Examples
+/// ```
+/// # use std::sync::Arc;
+/// # use syncterm::observer::ServerEvent;
+/// # use syncterm::server::ServerOptions;
+/// # struct App();
+/// # impl App {
+/// fn options(&self) -> ServerOptions {
+///     ServerOptions::new().observer(Arc::new(|event: &ServerEvent| {
+///         if let ServerEvent::Connected { client, addr } = *event {
+///             println!("{:?} joined from {}", client, addr);
+///         }
+///     }))
+/// }
+/// # }
+/// ```
+pub trait ServerObserver: Send + Sync {
+    fn observe(&self, event: &ServerEvent);
+}
+
+impl<F> ServerObserver for F
+where
+    F: Fn(&ServerEvent) + Send + Sync,
+{
+    fn observe(&self, event: &ServerEvent) {
+        self(event)
+    }
+}
+
+/// The default observer, which logs connections at `info`, traffic at `debug` (and each frame
+/// written at `trace`), and anything going wrong at `warn` or `error`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogObserver;
+
+impl ServerObserver for LogObserver {
+    fn observe(&self, event: &ServerEvent) {
+        match *event {
+            ServerEvent::Connected { client, addr } => {
+                info!("{:?} connected from {}", client, addr)
+            }
+            ServerEvent::Rejected { addr } => info!("Refused a connection from {}", addr),
+            ServerEvent::Disconnected {
+                client,
+                failed: false,
+            } => info!("{:?} disconnected", client),
+            ServerEvent::Disconnected {
+                client,
+                failed: true,
+            } => warn!("{:?} disconnected after its connection failed", client),
+            ServerEvent::InputReceived { client, bytes } => {
+                debug!("{:?} sent {} bytes", client, bytes)
+            }
+            ServerEvent::InputProcessed { client, elapsed } => {
+                debug!("Processed input from {:?} in {:?}", client, elapsed)
+            }
+            ServerEvent::Broadcast { clients } => debug!("Relayed to {} clients", clients),
+            ServerEvent::FrameSent { client, bytes } => {
+                trace!("Wrote {} bytes to {:?}", bytes, client)
+            }
+            ServerEvent::Replied {
+                client,
+                delivered: true,
+            } => debug!("Replied to {:?}", client),
+            ServerEvent::Replied {
+                client,
+                delivered: false,
+            } => debug!("{:?} left before its reply was ready", client),
+            ServerEvent::DecodeError {
+                client,
+                ref line,
+                ref error,
+            } => warn!("{:?} sent undecodable input {:?}: {}", client, line, error),
+            ServerEvent::SlowClient { client, queued } => {
+                warn!("{:?} has {} frames waiting to be written", client, queued)
+            }
+            ServerEvent::InputPanicked { client } => {
+                error!("Processing input from {:?} panicked, dropping it", client)
+            }
+            ServerEvent::Ignored { client, ref reason } => {
+                warn!("Ignoring input from {:?}: {}", client, reason)
+            }
+            ServerEvent::StreamError { client, ref error } => {
+                error!("Connection to {:?} failed: {}", client, error)
+            }
+            ServerEvent::AcceptError { ref error } => {
+                error!("Failed to accept a connection: {}", error)
+            }
+            ServerEvent::AdminCommand { ref command } => info!("Admin command: {}", command),
+        }
+    }
+}
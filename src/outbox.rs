@@ -0,0 +1,364 @@
+//! Holds on to a client's inputs while it's disconnected from the server, so that none are lost.
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+/// The longest to wait between attempts to reconnect to the server.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Returned by `ShellClient::outbox` to keep the client running when the connection to the
+/// server is lost, reconnecting in the background.
+///
+/// Inputs sent in the meantime are queued, up to `capacity` of them, and sent in order once the
+/// connection recovers. Inputs already sent but not yet acknowledged by the server are sent again,
+/// so the server may see an input twice if the connection was lost before its acknowledgement
+/// arrived.
+///
+/// # Examples
+/// ```
+/// # use std::time::Duration;
+/// # use syncterm::outbox::Outbox;
+/// let outbox = Outbox::new(100)
+///     .persist("/tmp/chat-outbox.jsonl")
+///     .retry_interval(Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    capacity: usize,
+    file: Option<PathBuf>,
+    retry_interval: Duration,
+}
+
+impl Outbox {
+    /// Queues up to `capacity` inputs while disconnected, retrying the connection every half
+    /// second at first.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            file: None,
+            retry_interval: Duration::from_millis(500),
+        }
+    }
+
+    /// Keeps every input the server hasn't acknowledged in a file, so that inputs still queued
+    /// when the client exits are sent the next time it runs.
+    ///
+    /// Inputs and their acknowledgements are appended to the file as they happen, and it's
+    /// compacted down to the inputs still held each time the client starts.
+    pub fn persist<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// Sets how long to wait before the first attempt to reconnect. Each failed attempt doubles
+    /// the wait, up to 30 seconds.
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+}
+
+/// Identifies an input sent through an outbox, in `ShellClient::on_delivery`.
+///
+/// Ids count up from 1, in the order the client's inputs were sent, with any inputs persisted
+/// from a previous run first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(pub(crate) u64);
+
+/// How far an input sent through an outbox has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// Waiting for the connection to recover
+    Queued,
+    /// Written to the connection
+    Sent,
+    /// Received by the server
+    Acknowledged,
+    /// Received by the server, which dropped it rather than processing it, such as a lockstep
+    /// input for a turn that had already ended
+    Rejected,
+    /// Discarded, because the outbox was full
+    Dropped,
+}
+
+struct Pending {
+    seq: u64,
+    // The lockstep turn the input is for, if any. Not persisted, since turns don't carry over
+    // to the next run.
+    turn: Option<u64>,
+    message: Value,
+    sent: bool,
+}
+
+/// A line of the file an outbox persists to. Inputs are numbered as they were in the run that
+/// wrote them.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Held(u64, Value),
+    Acknowledged(u64),
+}
+
+/// The inputs an outbox is holding on to, in the order they were sent.
+pub(crate) struct PendingMessages {
+    outbox: Outbox,
+    messages: VecDeque<Pending>,
+}
+
+impl PendingMessages {
+    pub fn new(outbox: Outbox) -> Self {
+        Self {
+            outbox,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Queues any inputs persisted by a previous run and not acknowledged, numbering them on from
+    /// `next_seq`, and compacts the file down to them. Returns the numbers they're given.
+    pub fn load(&mut self, next_seq: &mut u64) -> Vec<u64> {
+        let contents = match self.outbox.file {
+            Some(ref file) => fs::read_to_string(file).unwrap_or_default(),
+            None => return Vec::new(),
+        };
+
+        let mut held = Vec::new();
+        for record in contents
+            .lines()
+            .filter_map(|l| serde_json::from_str(l).ok())
+        {
+            match record {
+                Record::Held(seq, message) => held.push((seq, message)),
+                Record::Acknowledged(seq) => held.retain(|&(held_seq, _)| held_seq != seq),
+            }
+        }
+
+        let mut seqs = Vec::new();
+        for (_, message) in held {
+            *next_seq += 1;
+            seqs.push(*next_seq);
+            self.messages.push_back(Pending {
+                seq: *next_seq,
+                turn: None,
+                message,
+                sent: false,
+            });
+        }
+        self.compact();
+        seqs
+    }
+
+    pub fn retry_interval(&self, attempts: u32) -> Duration {
+        self.outbox
+            .retry_interval
+            .checked_mul(1 << attempts.min(16))
+            .map_or(MAX_RETRY_INTERVAL, |d| d.min(MAX_RETRY_INTERVAL))
+    }
+
+    /// Holds on to an input until it's acknowledged. Returns false, and doesn't, if too many
+    /// inputs are already queued.
+    pub fn push(&mut self, seq: u64, turn: Option<u64>, message: Value) -> bool {
+        let queued = self.messages.iter().filter(|p| !p.sent).count();
+        if queued >= self.outbox.capacity {
+            return false;
+        }
+
+        self.append(&Record::Held(seq, message.clone()));
+        self.messages.push_back(Pending {
+            seq,
+            turn,
+            message,
+            sent: false,
+        });
+        true
+    }
+
+    /// Returns the input numbered `seq`, and the turn it's for, if it's still held.
+    pub fn get(&self, seq: u64) -> Option<(Option<u64>, &Value)> {
+        self.messages
+            .iter()
+            .find(|p| p.seq == seq)
+            .map(|p| (p.turn, &p.message))
+    }
+
+    /// Returns every input held, in order, and whether each has already been sent.
+    pub fn held(&self) -> Vec<(u64, bool)> {
+        self.messages.iter().map(|p| (p.seq, p.sent)).collect()
+    }
+
+    pub fn mark_sent(&mut self, seq: u64) {
+        if let Some(pending) = self.messages.iter_mut().find(|p| p.seq == seq) {
+            pending.sent = true;
+        }
+    }
+
+    /// Forgets the sent input numbered `seq`, returning whether it was held.
+    ///
+    /// Only that input is forgotten, since servers relaying responses in completion order
+    /// acknowledge inputs out of order.
+    pub fn acknowledge(&mut self, seq: u64) -> bool {
+        let position = self.messages.iter().position(|p| p.sent && p.seq == seq);
+        match position {
+            Some(i) => {
+                self.messages.remove(i);
+                if self.messages.is_empty() {
+                    self.compact();
+                } else {
+                    self.append(&Record::Acknowledged(seq));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a line to the file.
+    fn append(&self, record: &Record) {
+        let file = match self.outbox.file {
+            Some(ref file) => file,
+            None => return,
+        };
+
+        let mut line = serde_json::to_vec(record).unwrap_or_default();
+        line.push(b'\n');
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .and_then(|mut f| f.write_all(&line));
+        if let Err(e) = appended {
+            warn!("Failed to persist the outbox to {:?}: {:?}", file, e);
+        }
+    }
+
+    /// Rewrites the file with only the inputs still held.
+    fn compact(&self) {
+        let file = match self.outbox.file {
+            Some(ref file) => file,
+            None => return,
+        };
+
+        let mut contents = Vec::new();
+        for pending in &self.messages {
+            let record = Record::Held(pending.seq, pending.message.clone());
+            let _ = serde_json::to_writer(&mut contents, &record);
+            let _ = contents.write_all(b"\n");
+        }
+        if let Err(e) = fs::write(file, contents) {
+            warn!("Failed to persist the outbox to {:?}: {:?}", file, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn sent(outbox: Outbox, seqs: &[u64]) -> PendingMessages {
+        let mut pending = PendingMessages::new(outbox);
+        for &seq in seqs {
+            assert!(pending.push(seq, None, Value::from(seq)));
+            pending.mark_sent(seq);
+        }
+        pending
+    }
+
+    #[test]
+    fn acknowledges_only_the_numbered_input() {
+        let mut pending = sent(Outbox::new(10), &[1, 2, 3]);
+
+        assert!(pending.acknowledge(2));
+        assert_eq!(pending.held(), vec![(1, true), (3, true)]);
+        assert!(!pending.acknowledge(2));
+        assert!(pending.acknowledge(3));
+        assert!(pending.acknowledge(1));
+        assert!(pending.held().is_empty());
+    }
+
+    #[test]
+    fn holds_unsent_inputs_until_sent() {
+        let mut pending = PendingMessages::new(Outbox::new(10));
+        assert!(pending.push(1, Some(4), Value::from("a")));
+
+        assert!(!pending.acknowledge(1));
+        assert_eq!(pending.held(), vec![(1, false)]);
+        assert_eq!(pending.get(1), Some((Some(4), &Value::from("a"))));
+
+        pending.mark_sent(1);
+        assert!(pending.acknowledge(1));
+        assert_eq!(pending.get(1), None);
+    }
+
+    #[test]
+    fn drops_inputs_once_full_of_unsent_ones() {
+        let mut pending = sent(Outbox::new(1), &[1]);
+
+        assert!(pending.push(2, None, Value::from(2)));
+        assert!(!pending.push(3, None, Value::from(3)));
+        assert_eq!(pending.held(), vec![(1, true), (2, false)]);
+    }
+
+    #[test]
+    fn backs_off_up_to_a_limit() {
+        let pending = PendingMessages::new(Outbox::new(1).retry_interval(Duration::from_secs(1)));
+
+        assert_eq!(pending.retry_interval(0), Duration::from_secs(1));
+        assert_eq!(pending.retry_interval(2), Duration::from_secs(4));
+        assert_eq!(pending.retry_interval(100), MAX_RETRY_INTERVAL);
+    }
+
+    #[test]
+    fn persists_unacknowledged_inputs_for_the_next_run() {
+        let file = env::temp_dir().join(format!("syncterm-outbox-test-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&file);
+        let outbox = Outbox::new(10).persist(&file);
+
+        let mut pending = sent(outbox.clone(), &[1, 2, 3]);
+        pending.acknowledge(2);
+
+        let mut next_seq = 10;
+        let mut reloaded = PendingMessages::new(outbox.clone());
+        let seqs = reloaded.load(&mut next_seq);
+        let compacted = fs::read_to_string(&file).unwrap();
+
+        assert_eq!(seqs, vec![11, 12]);
+        assert_eq!(next_seq, 12);
+        assert_eq!(reloaded.held(), vec![(11, false), (12, false)]);
+        assert_eq!(reloaded.get(11), Some((None, &Value::from(1))));
+        assert_eq!(reloaded.get(12), Some((None, &Value::from(3))));
+        assert_eq!(compacted.lines().count(), 2);
+
+        // Acknowledged by the number the reloaded run gave it
+        reloaded.mark_sent(11);
+        reloaded.acknowledge(11);
+        let mut next_seq = 0;
+        let seqs = PendingMessages::new(outbox).load(&mut next_seq);
+        let _ = fs::remove_file(&file);
+
+        assert_eq!(seqs, vec![1]);
+    }
+
+    #[test]
+    fn appends_to_the_file_until_every_input_is_acknowledged() {
+        let file = env::temp_dir().join(format!("syncterm-outbox-append-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&file);
+        let lines = || fs::read_to_string(&file).unwrap().lines().count();
+
+        let mut pending = sent(Outbox::new(10).persist(&file), &[1, 2, 3]);
+        assert_eq!(lines(), 3);
+        pending.acknowledge(1);
+        pending.acknowledge(3);
+        assert_eq!(lines(), 5);
+        pending.acknowledge(2);
+        let emptied = lines();
+        let _ = fs::remove_file(&file);
+
+        assert_eq!(emptied, 0);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+/// A client's own inputs that a synced server hasn't acknowledged yet, kept so that they can be
+/// re-applied on top of each authoritative state that arrives.
+pub(crate) struct Predictor<M> {
+    unacked: VecDeque<(u64, M)>,
+}
+
+impl<M> Predictor<M> {
+    pub fn new() -> Self {
+        Self {
+            unacked: VecDeque::new(),
+        }
+    }
+
+    /// Predicts the state after the numbered `input`, on top of `authoritative` and every input
+    /// still unacknowledged.
+    ///
+    /// Returns `None`, and forgets the input, if there is no state yet or `predict` declines it.
+    pub fn push<R, F>(
+        &mut self,
+        seq: u64,
+        input: M,
+        authoritative: Option<&Value>,
+        predict: F,
+    ) -> Option<R>
+    where
+        R: DeserializeOwned,
+        F: Fn(&mut R, &M) -> bool,
+    {
+        let mut state = self.replay(authoritative?, &predict)?;
+        if !predict(&mut state, &input) {
+            return None;
+        }
+
+        self.unacked.push_back((seq, input));
+        Some(state)
+    }
+
+    /// Forgets every input up to and including `ack`, then predicts the state after re-applying
+    /// the rest on top of `authoritative`.
+    pub fn reconcile<R, F>(
+        &mut self,
+        ack: Option<u64>,
+        authoritative: &Value,
+        predict: F,
+    ) -> Option<R>
+    where
+        R: DeserializeOwned,
+        F: Fn(&mut R, &M) -> bool,
+    {
+        if let Some(ack) = ack {
+            while self.unacked.front().is_some_and(|&(seq, _)| seq <= ack) {
+                self.unacked.pop_front();
+            }
+        }
+
+        self.replay(authoritative, &predict)
+    }
+
+    fn replay<R, F>(&self, authoritative: &Value, predict: &F) -> Option<R>
+    where
+        R: DeserializeOwned,
+        F: Fn(&mut R, &M) -> bool,
+    {
+        // The authoritative state is kept as JSON, to patch it, so it's decoded again each time
+        let mut state = match serde_json::from_value(authoritative.clone()) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Failed to decode the synced state: {}", e);
+                return None;
+            }
+        };
+        for (_, input) in &self.unacked {
+            predict(&mut state, input);
+        }
+
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(state: &mut Vec<u32>, input: &u32) -> bool {
+        state.push(*input);
+        true
+    }
+
+    fn state(moves: &[u32]) -> Value {
+        Value::from(moves.to_vec())
+    }
+
+    #[test]
+    fn replays_unacknowledged_inputs_on_top_of_the_servers_state() {
+        let mut predictor = Predictor::new();
+        let server = state(&[9]);
+
+        let first: Option<Vec<u32>> = predictor.push(1, 1, Some(&server), apply);
+        let second: Option<Vec<u32>> = predictor.push(2, 2, Some(&server), apply);
+        assert_eq!(first, Some(vec![9, 1]));
+        assert_eq!(second, Some(vec![9, 1, 2]));
+
+        // Another client's input lands first
+        let reconciled: Option<Vec<u32>> = predictor.reconcile(None, &state(&[9, 8]), apply);
+        assert_eq!(reconciled, Some(vec![9, 8, 1, 2]));
+
+        // Acknowledged inputs are part of the server's state, so aren't applied again
+        let reconciled: Option<Vec<u32>> = predictor.reconcile(Some(1), &state(&[9, 8, 1]), apply);
+        assert_eq!(reconciled, Some(vec![9, 8, 1, 2]));
+        let reconciled: Option<Vec<u32>> =
+            predictor.reconcile(Some(2), &state(&[9, 8, 1, 2]), apply);
+        assert_eq!(reconciled, Some(vec![9, 8, 1, 2]));
+    }
+
+    #[test]
+    fn forgets_inputs_it_cant_predict() {
+        let mut predictor = Predictor::new();
+        let decline = |_: &mut Vec<u32>, _: &u32| false;
+
+        let unpredicted: Option<Vec<u32>> = predictor.push(1, 1, None, apply);
+        let declined: Option<Vec<u32>> = predictor.push(2, 2, Some(&state(&[])), decline);
+        assert_eq!(unpredicted, None);
+        assert_eq!(declined, None);
+
+        let reconciled: Option<Vec<u32>> = predictor.reconcile(None, &state(&[9]), apply);
+        assert_eq!(reconciled, Some(vec![9]));
+    }
+
+    #[test]
+    fn predicts_nothing_from_a_state_it_cant_decode() {
+        let mut predictor = Predictor::new();
+
+        let predicted: Option<Vec<u32>> = predictor.push(1, 1, Some(&Value::from("9")), apply);
+        assert_eq!(predicted, None);
+    }
+}
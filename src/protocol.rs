@@ -0,0 +1,71 @@
+//! The frames exchanged between `client::connect` and `server::spawn_shell_and_listen`.
+//!
+//! Each frame is serialized as a single line of JSON.
+
+use lockstep::TurnInfo;
+use sync::StateUpdate;
+
+/// Identifies a request sent with `KeyAction::Request`, and the reply the server sends back.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(pub(crate) u64);
+
+/// Sent from a client to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ClientFrame<M> {
+    /// An input whose response is relayed to every client, numbered in the order the client sent
+    /// its inputs.
+    Input(u64, M),
+    /// An input for the numbered lockstep turn, numbered as `Input` is.
+    TurnInput(u64, u64, M),
+    /// An input whose response is sent back to this client only.
+    Request(RequestId, M),
+}
+
+impl<M> ClientFrame<M> {
+    /// The numbered input, for a lockstep turn if it's sent for one.
+    pub fn input(seq: u64, turn: Option<u64>, message: M) -> Self {
+        match turn {
+            Some(turn) => ClientFrame::TurnInput(seq, turn, message),
+            None => ClientFrame::Input(seq, message),
+        }
+    }
+}
+
+/// Sent from a client about its connection, rather than as an input to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ControlFrame {
+    /// Measures the round-trip time, answered by the stream reader itself rather than by the
+    /// server.
+    Ping(u64),
+    /// Asks a synced server for a snapshot of its state, after missing a delta.
+    Resync,
+}
+
+/// Sent from the server to a client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum ServerFrame<R> {
+    /// A response relayed to every client.
+    Broadcast(R),
+    /// The response to one of this client's requests.
+    Reply(RequestId, R),
+    /// One of this client's requests won't be answered, because the client is muted or the
+    /// server doesn't take requests.
+    Refused(RequestId),
+    /// The start of a lockstep turn.
+    TurnStart(TurnInfo),
+    /// A new version of a synced server's state, the number of the last input from this client
+    /// that it includes, and when it was sent in milliseconds since the Unix epoch.
+    State {
+        version: u64,
+        update: StateUpdate,
+        ack: Option<u64>,
+        time: f64,
+    },
+    /// The numbered input from this client has been processed.
+    Ack(u64),
+    /// The numbered input from this client was dropped rather than processed, such as a lockstep
+    /// input for a turn that had already ended.
+    Rejected(u64),
+    /// The answer to the numbered `ControlFrame::Ping`.
+    Pong(u64),
+}
@@ -1,12 +1,28 @@
+use std::collections::{BTreeMap, HashMap};
 use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use serde::{Serialize, de::DeserializeOwned};
 use serde_json;
 
+use admin::{self, Admin, Control};
+use metrics::{self, Metrics, Recorder, ServerStats};
+use observer::{LogObserver, ServerEvent, ServerObserver, SLOW_CLIENT_QUEUE};
+use protocol::{ClientFrame, ControlFrame, RequestId, ServerFrame};
+
+/// How long to wait before accepting connections again, after failing to accept one.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a server that's shutting down waits for what's queued for its clients to be written,
+/// before closing their connections anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Trait implemented by a struct to define customizable functionality for a synchronized
 /// command-line app server.
 ///
@@ -24,213 +40,1118 @@ where
     /// Process input received from a single client. The returned value will be relayed to all
     /// clients.
     ///
-    /// This function will be synchronously called on inputs in the order that they are received
-    /// from clients.
+    /// By default this function will be synchronously called on inputs in the order that they are
+    /// received from clients. If `worker_pool` returns a pool, it is instead called concurrently
+    /// from the pool's worker threads, so it should not mutate server state - do that in
+    /// `commit_response`.
     ///
     /// # Examples
     /// ```no_run
+    /// # struct App();
+    /// # impl App {
     /// fn process_input(&self, input: String) -> String {
     ///     input.to_uppercase()
     /// }
+    /// # }
     /// ```
     fn process_input(&self, client_message: M) -> R;
+
+    /// Process a request received from a single client, sent with `KeyAction::Request`. The
+    /// returned value will be sent back to the requesting client only.
+    ///
+    /// Called under the same rules as `process_input`, which it defaults to.
+    fn process_request(&self, client_message: M) -> R {
+        self.process_input(client_message)
+    }
+
+    /// Returns the worker pool to run `process_input` on, if any.
+    ///
+    /// Defaults to `None`, which runs `process_input` inline on the server's main loop: one slow
+    /// input then holds up every other client's input until it finishes.
+    ///
+    /// The workers share the server, so only `spawn_shell`, which requires the server to be
+    /// `Send + Sync`, runs inputs on the pool; `spawn_shell_and_listen` ignores it.
+    fn worker_pool(&self) -> Option<WorkerPool> {
+        None
+    }
+
+    /// Called with each response returned by `process_input`, before it is relayed to clients.
+    /// The returned value is what gets relayed.
+    ///
+    /// This function is always called from the server's main loop, one response at a time and in
+    /// the order responses are relayed, so it is the place to serialize mutations of server
+    /// state when running with a worker pool.
+    fn commit_response(&self, response: R) -> R {
+        response
+    }
+
+    /// Returns what to report the server's events to, whether to collect statistics about it
+    /// and where to take admin commands, once when the server starts. Defaults to
+    /// `ServerOptions::new()`.
+    fn options(&self) -> ServerOptions {
+        ServerOptions::new()
+    }
+
+    /// Returns who sent a message, if it says, to be listed alongside the client's address in
+    /// the admin `list` command. Called with every input and request before it's processed.
+    fn identify(&self, _client_message: &M) -> Option<String> {
+        None
+    }
+
+    /// Returns the response to broadcast for an operator's announcement, sent with the admin
+    /// `broadcast` command. It's relayed to clients as is, without `commit_response`. Defaults
+    /// to `None`, which refuses announcements.
+    fn announce(&self, _text: &str) -> Option<R> {
+        None
+    }
+}
+
+/// The order in which responses computed by a [WorkerPool](struct.WorkerPool.html) are relayed
+/// to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseOrder {
+    /// Relays responses in the order their inputs were received, holding back fast responses
+    /// until every earlier input has been processed.
+    Input,
+    /// Relays each response as soon as it has been computed.
+    Completion,
+}
+
+/// Configuration for running `ShellServer::process_input` on a pool of worker threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkerPool {
+    threads: usize,
+    order: ResponseOrder,
 }
 
+impl WorkerPool {
+    /// A pool of `threads` workers (at least one) relaying responses in input order.
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            order: ResponseOrder::Input,
+        }
+    }
+
+    /// Sets the order in which responses are relayed to clients.
+    pub fn order(mut self, order: ResponseOrder) -> Self {
+        self.order = order;
+        self
+    }
+}
+
+/// How to run a server of any kind, returned by the `options` method of `ShellServer`,
+/// `SyncedServer` and `LockstepServer`.
+///
+/// # Examples
+/// ```
+/// # use std::sync::Arc;
+/// # use syncterm::metrics::Metrics;
+/// # use syncterm::observer::ServerEvent;
+/// # use syncterm::server::ServerOptions;
+/// # struct App();
+/// # impl App {
+/// fn options(&self) -> ServerOptions {
+///     ServerOptions::new()
+///         .observer(Arc::new(|event: &ServerEvent| println!("{:?}", event)))
+///         .metrics(Metrics::new().serve("127.0.0.1:9100"))
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub(crate) observer: Arc<dyn ServerObserver>,
+    pub(crate) metrics: Option<Metrics>,
+    pub(crate) admin: Option<Admin>,
+}
+
+impl ServerOptions {
+    /// Reports the server's events to a `LogObserver`, which logs them through the `log` crate,
+    /// collects no statistics and takes no admin commands.
+    pub fn new() -> Self {
+        Self {
+            observer: Arc::new(LogObserver),
+            metrics: None,
+            admin: None,
+        }
+    }
+
+    /// Sets what to report the server's connections, traffic and errors to.
+    pub fn observer(mut self, observer: Arc<dyn ServerObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Collects statistics about the server: counts of its connections and traffic, how long it
+    /// takes to process inputs, and how far behind each client is.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Accepts commands from the server's operator: to list, kick, ban and mute clients, make
+    /// announcements and shut the server down.
+    ///
+    /// See the [admin](../admin/index.html) module for the commands.
+    pub fn admin(mut self, admin: Admin) -> Self {
+        self.admin = Some(admin);
+        self
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Identifies a client connection for as long as the server runs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(pub(crate) u64);
+
+/// Something that happened on a client connection, passed from its stream threads to the
+/// server's main loop.
+pub(crate) enum ClientEvent<M> {
+    Connected,
+    Frame(ClientFrame<M>),
+    /// The client asked for a snapshot of a synced server's state.
+    Resync,
+    Disconnected,
+}
+
+/// Something for a server's main loop to handle.
+pub(crate) enum Event<M> {
+    Client(ClientId, ClientEvent<M>),
+    /// An operator's announcement, answered with how many clients it reached. Servers that make
+    /// no announcements drop the answering sender.
+    Announce(String, Sender<Option<usize>>),
+}
+
+/// Where a response returned by the ShellServer should be relayed to.
+#[derive(Debug, Clone, Copy)]
+enum Destination {
+    /// Every client, after which the sender's input is acknowledged
+    AllClients(ClientId, u64),
+    Requester(ClientId, RequestId),
+}
+
+/// Passes frames to a client's writing thread, counting how many are waiting to be written.
+pub(crate) struct ClientSender<R> {
+    client: ClientId,
+    sx: Sender<ServerFrame<R>>,
+    queued: Arc<AtomicUsize>,
+    observer: Arc<dyn ServerObserver>,
+}
+
+impl<R> Clone for ClientSender<R> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client,
+            sx: self.sx.clone(),
+            queued: self.queued.clone(),
+            observer: self.observer.clone(),
+        }
+    }
+}
+
+impl<R> ClientSender<R> {
+    pub fn new(
+        client: ClientId,
+        sx: Sender<ServerFrame<R>>,
+        queued: Arc<AtomicUsize>,
+        observer: Arc<dyn ServerObserver>,
+    ) -> Self {
+        Self {
+            client,
+            sx,
+            queued,
+            observer,
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the client's writing thread is still running.
+    pub fn send(&self, frame: ServerFrame<R>) -> bool {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.sx.send(frame).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+
+        if queued == SLOW_CLIENT_QUEUE {
+            self.observer.observe(&ServerEvent::SlowClient {
+                client: self.client,
+                queued,
+            });
+        }
+        true
+    }
+}
+
+pub(crate) type StreamSenders<R> = Arc<Mutex<HashMap<ClientId, ClientSender<R>>>>;
+
 /// The "main" function for ShellServers.
 ///
 /// Binds a listener to the ShellServer's local address, handles client connections, pipes client
 /// inputs to the server's `process_input` method, and relays the returned response to all active
 /// client connections.
 ///
-/// Errors if the listener fails to bind.
+/// Inputs are always processed on the main loop: a server's worker pool is ignored, with a
+/// warning, since the workers would have to share the server between threads. Such servers are
+/// run on their pool by `spawn_shell`.
+///
+/// Returns once the server is shut down, through its admin socket. Errors if the listener, or the
+/// server's metrics or admin socket, fail to bind.
 pub fn spawn_shell_and_listen<M, R, S>(server: S) -> Result<(), String>
 where
     M: DeserializeOwned + Send + 'static,
     R: Serialize + Send + 'static + Clone,
     S: ShellServer<M, R>,
 {
-    let addr = server.local_address();
+    if server.worker_pool().is_some() {
+        warn!("Ignoring the server's worker pool, which only spawn_shell runs inputs on");
+    }
+
+    let runtime = start(&server.local_address(), server.options())?;
+    run(runtime, |runtime| run_shell(&server, runtime));
+    Ok(())
+}
+
+/// Like `spawn_shell_and_listen`, but runs the server in the background, returning a handle to
+/// it once it's listening. Unlike `spawn_shell_and_listen`, it runs inputs on the server's worker
+/// pool, if it has one, which is why the server has to be shareable between threads.
+///
+/// Errors if the listener, or the server's metrics or admin socket, fail to bind.
+pub fn spawn_shell<M, R, S>(server: S) -> Result<ServerHandle, String>
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+    S: ShellServer<M, R> + Send + Sync + 'static,
+{
+    let server = Arc::new(server);
+    let runtime = start(&server.local_address(), server.options())?;
+
+    Ok(ServerHandle::spawn(runtime, move |runtime| {
+        match server.worker_pool() {
+            None => run_shell(&*server, runtime),
+            Some(pool) => {
+                let Runtime {
+                    events: stm_shl_rx,
+                    senders: shl_stm_sxs,
+                    observer,
+                    control,
+                    ..
+                } = runtime;
+                dispatch_to_worker_pool(pool, stm_shl_rx, &shl_stm_sxs, server, observer, control)
+            }
+        }
+    }))
+}
+
+/// The main loop for ShellServers without a worker pool, which runs until every client has gone
+/// and the server is shut down.
+fn run_shell<M, R, S>(server: &S, runtime: Runtime<M, R>)
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+    S: ShellServer<M, R>,
+{
+    let Runtime {
+        events: stm_shl_rx,
+        senders: shl_stm_sxs,
+        observer,
+        control,
+        ..
+    } = runtime;
+    while pipe_stream_to_shell_and_relay_response(
+        &stm_shl_rx,
+        &shl_stm_sxs,
+        server,
+        &*observer,
+        &control,
+    ) {}
+}
+
+/// Everything a server's main loop runs on: its clients' events, the senders relaying frames back
+/// to them, and what its events are reported to.
+pub(crate) struct Runtime<M, R> {
+    pub events: Receiver<Event<M>>,
+    pub senders: StreamSenders<R>,
+    pub observer: Arc<dyn ServerObserver>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub control: Arc<Control>,
+}
+
+/// Starts a server of any kind on `addr`, as set up by its `options`: accepting connections,
+/// collecting and serving statistics, and taking admin commands.
+///
+/// Errors if the listener, or the server's metrics or admin socket, fail to bind.
+pub(crate) fn start<M, R>(addr: &str, options: ServerOptions) -> Result<Runtime<M, R>, String>
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+{
+    let (observer, recorder) = metrics::record(options.observer, options.metrics);
+    let control = Arc::new(Control::default());
+    let (stm_shl_sx, events) = channel::<Event<M>>();
+    let stm_shl_sx = Arc::new(stm_shl_sx);
+    let announcer = Arc::downgrade(&stm_shl_sx);
+    let senders = listen(addr, stm_shl_sx, observer.clone(), control.clone())?;
+
+    // Announcements are made on the main loop, so that servers needn't be shared between threads.
+    // Only the accepting thread holds on to the main loop's sender, so that it isn't kept running
+    // by announcements once that thread has stopped.
+    control.set_announce(Arc::new(move |text: &str| {
+        let (answer_sx, answer_rx) = channel();
+        announcer
+            .upgrade()?
+            .send(Event::Announce(text.to_owned(), answer_sx))
+            .ok()?;
+        answer_rx.recv().ok()?
+    }));
+    if let Some(ref recorder) = recorder {
+        recorder.start(&senders)?;
+    }
+    if let Some(admin) = options.admin {
+        admin::serve(admin, control.clone(), observer.clone())?;
+    }
+
+    Ok(Runtime {
+        events,
+        senders,
+        observer,
+        recorder,
+        control,
+    })
+}
+
+/// Returned by `spawn_shell`, `sync::spawn_synced` and `lockstep::spawn_lockstep` to keep an eye
+/// on a server running in the background.
+pub struct ServerHandle {
+    thread: thread::JoinHandle<()>,
+    recorder: Option<Arc<Recorder>>,
+    control: Arc<Control>,
+}
+
+impl ServerHandle {
+    /// Runs a server's main loop on a thread of its own.
+    pub(crate) fn spawn<M, R, F>(runtime: Runtime<M, R>, main_loop: F) -> Self
+    where
+        M: Send + 'static,
+        R: Send + 'static,
+        F: FnOnce(Runtime<M, R>) + Send + 'static,
+    {
+        let recorder = runtime.recorder.clone();
+        let control = runtime.control.clone();
+        Self {
+            thread: thread::spawn(move || run(runtime, main_loop)),
+            recorder,
+            control,
+        }
+    }
+
+    /// Returns the server's statistics so far, if its `options` turned them on.
+    pub fn stats(&self) -> Option<ServerStats> {
+        self.recorder.as_ref().map(|recorder| recorder.stats())
+    }
+
+    /// Stops the server, as the admin `shutdown` command does: it stops accepting connections and
+    /// inputs, then closes every client's connection once the inputs already received have been
+    /// answered.
+    pub fn shutdown(&self) {
+        self.control.shutdown();
+    }
+
+    /// Blocks until the server stops, once it's shut down.
+    pub fn join(self) {
+        if let Err(e) = self.thread.join() {
+            panic::resume_unwind(e);
+        }
+    }
+}
+
+/// Runs a server's main loop until it ends, once the server is shut down, then closes every
+/// client's connection once what's queued for it has been written.
+pub(crate) fn run<M, R, F>(runtime: Runtime<M, R>, main_loop: F)
+where
+    F: FnOnce(Runtime<M, R>),
+{
+    let senders = runtime.senders.clone();
+    let control = runtime.control.clone();
+    main_loop(runtime);
+
+    // Each writing thread stops, and closes its connection, once it's written what's queued
+    senders
+        .lock()
+        .expect("Poisoned map of outgoing sxs")
+        .clear();
+    control.wait_for_clients(SHUTDOWN_TIMEOUT);
+}
+
+/// Binds a listener to `addr` and starts accepting client connections in the background, passing
+/// every client's events to `stm_shl_sx`.
+///
+/// Returns the senders relaying frames back to each connected client. Connections and traffic are
+/// reported to `observer`, and connections are kept track of in `control`, which can close them.
+pub(crate) fn listen<M, R>(
+    addr: &str,
+    stm_shl_sx: Arc<Sender<Event<M>>>,
+    observer: Arc<dyn ServerObserver>,
+    control: Arc<Control>,
+) -> Result<StreamSenders<R>, String>
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+{
     let listener =
-        TcpListener::bind(&addr).map_err(|e| format!("Failed to bind to {:?}: {:?}", addr, e))?;
+        TcpListener::bind(addr).map_err(|e| format!("Failed to bind to {:?}: {:?}", addr, e))?;
+    if let Ok(local_addr) = listener.local_addr() {
+        control.set_local_addr(local_addr);
+    }
 
-    let (stm_shl_sx, stm_shl_rx) = channel::<M>();
-    let shl_stm_sxs = Arc::new(Mutex::new(Vec::new()));
+    let shl_stm_sxs = Arc::new(Mutex::new(HashMap::new()));
 
     let sxs = shl_stm_sxs.clone();
     thread::spawn(move || {
-        handle_incoming_streams(sxs, listener, stm_shl_sx);
+        handle_incoming_streams(sxs, listener, &stm_shl_sx, observer, control);
     });
 
-    loop {
-        pipe_stream_to_shell_and_relay_response(&stm_shl_rx, &shl_stm_sxs, &server);
+    Ok(shl_stm_sxs)
+}
+
+/// Sends `frame` to one client, forgetting it if it's gone away.
+///
+/// Returns whether the client was still connected.
+pub(crate) fn send_to<R>(
+    client: ClientId,
+    frame: ServerFrame<R>,
+    shl_stm_sxs: &StreamSenders<R>,
+) -> bool
+where
+    R: Serialize + Send + 'static + Clone,
+{
+    let mut guard = shl_stm_sxs.lock().expect("Poisoned map of outgoing sxs");
+    let sent = guard
+        .get(&client)
+        .is_some_and(|shl_stm_sx| shl_stm_sx.send(frame));
+    if !sent {
+        guard.remove(&client);
     }
+    sent
 }
 
+/// Sends `frame` to every connected client, forgetting those that have gone away.
+///
+/// Returns the number of clients relayed to.
+pub(crate) fn broadcast<R>(frame: ServerFrame<R>, shl_stm_sxs: &StreamSenders<R>) -> usize
+where
+    R: Serialize + Send + 'static + Clone,
+{
+    let mut guard = shl_stm_sxs.lock().expect("Poisoned map of outgoing sxs");
+    guard.retain(|_, shl_stm_sx| shl_stm_sx.send(frame.clone()));
+    guard.len()
+}
+
+/// Handles one event from a client. Returns false once every client has disconnected and the
+/// server has stopped accepting connections.
 fn pipe_stream_to_shell_and_relay_response<M, R, S>(
-    stm_shl_rx: &Receiver<M>,
-    shl_stm_sxs: &Arc<Mutex<Vec<Sender<R>>>>,
+    stm_shl_rx: &Receiver<Event<M>>,
+    shl_stm_sxs: &StreamSenders<R>,
     server: &S,
-) where
+    observer: &dyn ServerObserver,
+    control: &Control,
+) -> bool
+where
     M: DeserializeOwned + Send + 'static,
     R: Serialize + Send + 'static + Clone,
     S: ShellServer<M, R>,
 {
-    let input = stm_shl_rx.recv().expect("Nothing to receive");
+    let (client, frame) = match stm_shl_rx.recv() {
+        Ok(Event::Client(client, ClientEvent::Frame(frame))) => (client, frame),
+        Ok(Event::Announce(text, answer)) => {
+            let _ = answer.send(announce(server, &text, shl_stm_sxs, observer));
+            return true;
+        }
+        Ok(_) => return true,
+        Err(_) => return false,
+    };
 
-    let response = server.process_input(input);
+    let (destination, response) = process_frame(server, client, frame, observer, control);
 
-    let mut guard = shl_stm_sxs.lock().expect("Poisoned Vec of outgoing sxs");
-    guard.retain(|shl_stm_sx| shl_stm_sx.send(response.clone()).is_ok());
+    relay_response(
+        server.commit_response(response),
+        destination,
+        shl_stm_sxs,
+        observer,
+    );
+    true
+}
 
-    println!("MAIN: {} clients relayed to", guard.len());
+/// Broadcasts the server's announcement of an operator's `text`, if it makes one, returning how
+/// many clients it reached.
+fn announce<M, R, S>(
+    server: &S,
+    text: &str,
+    shl_stm_sxs: &StreamSenders<R>,
+    observer: &dyn ServerObserver,
+) -> Option<usize>
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+    S: ShellServer<M, R>,
+{
+    let response = server.announce(text)?;
+    let relayed = broadcast(ServerFrame::Broadcast(response), shl_stm_sxs);
+    observer.observe(&ServerEvent::Broadcast { clients: relayed });
+    Some(relayed)
+}
+
+fn process_frame<M, R, S>(
+    server: &S,
+    client: ClientId,
+    frame: ClientFrame<M>,
+    observer: &dyn ServerObserver,
+    control: &Control,
+) -> (Destination, R)
+where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+    S: ShellServer<M, R>,
+{
+    let identity = match frame {
+        ClientFrame::Input(_, ref input)
+        | ClientFrame::TurnInput(_, _, ref input)
+        | ClientFrame::Request(_, ref input) => server.identify(input),
+    };
+    if let Some(identity) = identity {
+        control.identify(client, identity);
+    }
+
+    let started = Instant::now();
+    let processed = match frame {
+        ClientFrame::Input(seq, input) | ClientFrame::TurnInput(seq, _, input) => (
+            Destination::AllClients(client, seq),
+            server.process_input(input),
+        ),
+        ClientFrame::Request(id, input) => (
+            Destination::Requester(client, id),
+            server.process_request(input),
+        ),
+    };
+
+    observer.observe(&ServerEvent::InputProcessed {
+        client,
+        elapsed: started.elapsed(),
+    });
+    processed
+}
+
+fn relay_response<R>(
+    response: R,
+    destination: Destination,
+    shl_stm_sxs: &StreamSenders<R>,
+    observer: &dyn ServerObserver,
+) where
+    R: Serialize + Send + 'static + Clone,
+{
+    match destination {
+        Destination::AllClients(client, seq) => {
+            let relayed = broadcast(ServerFrame::Broadcast(response), shl_stm_sxs);
+            send_to(client, ServerFrame::Ack(seq), shl_stm_sxs);
+
+            observer.observe(&ServerEvent::Broadcast { clients: relayed });
+        }
+        Destination::Requester(client, id) => {
+            let delivered = send_to(client, ServerFrame::Reply(id, response), shl_stm_sxs);
+            observer.observe(&ServerEvent::Replied { client, delivered });
+        }
+    }
+}
+
+fn dispatch_to_worker_pool<M, R, S>(
+    pool: WorkerPool,
+    stm_shl_rx: Receiver<Event<M>>,
+    shl_stm_sxs: &StreamSenders<R>,
+    server: Arc<S>,
+    observer: Arc<dyn ServerObserver>,
+    control: Arc<Control>,
+) where
+    M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
+    S: ShellServer<M, R> + Send + Sync + 'static,
+{
+    let (job_sx, job_rx) = channel::<(u64, (ClientId, ClientFrame<M>))>();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (done_sx, done_rx) = channel::<(u64, Option<(Destination, R)>)>();
+
+    for _ in 0..pool.threads {
+        let jobs = job_rx.clone();
+        let done = done_sx.clone();
+        let server = server.clone();
+        let observer = observer.clone();
+        let control = control.clone();
+        thread::spawn(move || loop {
+            let job = jobs.lock().expect("Poisoned worker job queue").recv();
+            let (seq, (client, frame)) = match job {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+
+            // A panicking input still has to be accounted for, or input-ordered relaying would
+            // wait on it forever.
+            let response = panic::catch_unwind(AssertUnwindSafe(|| {
+                process_frame(&*server, client, frame, &*observer, &control)
+            }));
+            if response.is_err() {
+                observer.observe(&ServerEvent::InputPanicked { client });
+            }
+
+            if done.send((seq, response.ok())).is_err() {
+                break;
+            }
+        });
+    }
+    // So that relaying stops once the workers do
+    drop(done_sx);
+
+    // Tag each input with its arrival order before handing it to the workers, making
+    // announcements on the way, since they aren't committed
+    let sxs = shl_stm_sxs.clone();
+    let obs = observer.clone();
+    let announcer = server.clone();
+    thread::spawn(move || {
+        let frames = stm_shl_rx.iter().filter_map(|event| match event {
+            Event::Client(client, ClientEvent::Frame(frame)) => Some((client, frame)),
+            Event::Announce(text, answer) => {
+                let _ = answer.send(announce(&*announcer, &text, &sxs, &*obs));
+                None
+            }
+            Event::Client(..) => None,
+        });
+        for (seq, input) in (0..).zip(frames) {
+            if job_sx.send((seq, input)).is_err() {
+                break;
+            }
+        }
+    });
+
+    // The single-threaded phase: commit and relay responses one at a time
+    let mut next_seq = 0;
+    let mut held_back = BTreeMap::new();
+    for (seq, response) in done_rx.iter() {
+        match pool.order {
+            ResponseOrder::Completion => {
+                if let Some((destination, response)) = response {
+                    relay_response(
+                        server.commit_response(response),
+                        destination,
+                        shl_stm_sxs,
+                        &*observer,
+                    );
+                }
+            }
+            ResponseOrder::Input => {
+                held_back.insert(seq, response);
+                while let Some(response) = held_back.remove(&next_seq) {
+                    next_seq += 1;
+                    if let Some((destination, response)) = response {
+                        relay_response(
+                            server.commit_response(response),
+                            destination,
+                            shl_stm_sxs,
+                            &*observer,
+                        );
+                    }
+                }
+            }
+        }
+    }
 }
 
 fn handle_incoming_streams<M, R>(
-    shl_stm_sxs: Arc<Mutex<Vec<Sender<R>>>>,
+    shl_stm_sxs: StreamSenders<R>,
     listener: TcpListener,
-    stm_shl_sx: Sender<M>,
+    stm_shl_sx: &Sender<Event<M>>,
+    observer: Arc<dyn ServerObserver>,
+    control: Arc<Control>,
 ) where
     M: DeserializeOwned + Send + 'static,
     R: Serialize + Send + 'static + Clone,
 {
-    for stream in listener.incoming() {
+    for (client, stream) in (0..).map(ClientId).zip(listener.incoming()) {
+        // Stops accepting, and drops this sender, so the main loop ends once every client's gone
+        if control.is_shutting_down() {
+            return;
+        }
+
         match stream {
-            Ok(mut stream) => {
+            Ok(stream) => {
                 let sx = stm_shl_sx.clone();
                 let sxs = shl_stm_sxs.clone();
+                let observer = observer.clone();
+                let control = control.clone();
                 thread::spawn(move || {
-                    handle_new_stream(sx, sxs, stream);
+                    handle_new_stream(client, sx, sxs, stream, observer, control);
                 });
             }
             Err(e) => {
-                panic!("Oh no: {}", e);
+                observer.observe(&ServerEvent::AcceptError {
+                    error: e.to_string(),
+                });
+                // Accepting keeps failing while out of file descriptors, so don't spin
+                thread::sleep(ACCEPT_RETRY_INTERVAL);
             }
         }
     }
 }
 
 fn handle_new_stream<M, R>(
-    stm_shl_sx: Sender<M>,
-    shl_stm_sxs: Arc<Mutex<Vec<Sender<R>>>>,
+    client: ClientId,
+    stm_shl_sx: Sender<Event<M>>,
+    shl_stm_sxs: StreamSenders<R>,
     stream: TcpStream,
+    observer: Arc<dyn ServerObserver>,
+    control: Arc<Control>,
 ) where
     M: DeserializeOwned + Send + 'static,
     R: Serialize + Send + 'static + Clone,
 {
-    // TODO: lock some item to prevent sending/receiving while threads spin up
-
-    println!(
-        "{:?}: Received connection from {}!",
-        thread::current().id(),
-        stream.peer_addr().expect("Failed to get stream remote IP")
-    );
+    // A connection that can't be set up is closed, by dropping it, before it's registered
+    let (addr, read_stream) = match stream
+        .peer_addr()
+        .and_then(|addr| stream.try_clone().map(|read_stream| (addr, read_stream)))
+    {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            observer.observe(&ServerEvent::AcceptError {
+                error: e.to_string(),
+            });
+            return;
+        }
+    };
+    if !control.register(client, addr, &stream) {
+        observer.observe(&ServerEvent::Rejected { addr });
+        return;
+    }
+    observer.observe(&ServerEvent::Connected { client, addr });
 
     let alive = Arc::new(Mutex::new(true));
 
+    // Register the client before reading from it, so that the main loop always hears about a
+    // client before its first input
+    let (shl_stm_sx, shl_stm_rx) = channel::<ServerFrame<R>>();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let shl_stm_sx = ClientSender::new(client, shl_stm_sx, queued.clone(), observer.clone());
+    {
+        shl_stm_sxs.lock().unwrap().insert(client, shl_stm_sx);
+    }
+    let _ = stm_shl_sx.send(Event::Client(client, ClientEvent::Connected));
+
     // Handle reading from the stream
-    let read_stream = stream.try_clone().unwrap();
     let al = alive.clone();
+    let obs = observer.clone();
+    let ctl = control.clone();
     let receive_handle = thread::spawn(move || {
-        receive_and_pass_along_line(read_stream, stm_shl_sx, al);
+        receive_and_pass_along_line(client, read_stream, stm_shl_sx, shl_stm_sxs, al, obs, ctl)
     });
 
     // Handle writing to the stream
-    let (shl_stm_sx, shl_stm_rx) = channel::<R>();
-    {
-        shl_stm_sxs.lock().unwrap().push(shl_stm_sx);
-    }
-    let response_handle = thread::spawn(move || relay_response_back(stream, shl_stm_rx, alive));
-
-    // TODO: Unlock read/write signal
+    let obs = observer.clone();
+    let response_handle =
+        thread::spawn(move || relay_response_back(client, stream, shl_stm_rx, queued, alive, obs));
 
-    if let Err(e) = receive_handle.join() {
-        println!(
-            "{:?}: Command receiver thread panicked with message {:?}",
-            thread::current().id(),
-            e
-        );
-    };
-    if let Err(e) = response_handle.join() {
-        println!(
-            "{:?}: Response relayer thread panicked with message {:?}",
-            thread::current().id(),
-            e
-        );
-    };
+    let read_failed = receive_handle.join().unwrap_or_else(|e| {
+        observer.observe(&ServerEvent::StreamError {
+            client,
+            error: format!("Reading thread panicked with message {:?}", e),
+        });
+        true
+    });
+    let write_failed = response_handle.join().unwrap_or_else(|e| {
+        observer.observe(&ServerEvent::StreamError {
+            client,
+            error: format!("Writing thread panicked with message {:?}", e),
+        });
+        true
+    });
+    control.unregister(client);
+    observer.observe(&ServerEvent::Disconnected {
+        client,
+        failed: read_failed || write_failed,
+    });
 }
 
-fn receive_and_pass_along_line<M>(stream: TcpStream, stm_shl: Sender<M>, alive: Arc<Mutex<bool>>)
+/// Passes a client's frames to the main loop until its connection closes. Returns whether reading
+/// failed, rather than the connection closing.
+fn receive_and_pass_along_line<M, R>(
+    client: ClientId,
+    stream: TcpStream,
+    stm_shl: Sender<Event<M>>,
+    shl_stm_sxs: StreamSenders<R>,
+    alive: Arc<Mutex<bool>>,
+    observer: Arc<dyn ServerObserver>,
+    control: Arc<Control>,
+) -> bool
 where
     M: DeserializeOwned + Send + 'static,
+    R: Serialize + Send + 'static + Clone,
 {
-    println!(
-        "{:?} reading input from {}",
-        thread::current().id(),
-        stream.peer_addr().unwrap()
-    );
-
-    let mut lines = BufReader::new(&stream).lines();
-    while let Some(maybe_line) = lines.next() {
+    let mut failed = false;
+    for maybe_line in BufReader::new(&stream).lines() {
         match maybe_line {
-            Ok(mut line) => {
-                let user_input = serde_json::from_str::<M>(&line).unwrap();
-                stm_shl.send(user_input).unwrap();
-            }
+            Ok(line) => match serde_json::from_str::<ClientFrame<M>>(&line) {
+                Ok(user_input) if control.is_muted(client) => {
+                    observer.observe(&ServerEvent::Ignored {
+                        client,
+                        reason: "muted by an operator".to_owned(),
+                    });
+                    // Still answered, so that the client isn't left waiting on them
+                    let answer = match user_input {
+                        ClientFrame::Input(seq, _) | ClientFrame::TurnInput(seq, _, _) => {
+                            ServerFrame::Ack(seq)
+                        }
+                        ClientFrame::Request(id, _) => ServerFrame::Refused(id),
+                    };
+                    send_to(client, answer, &shl_stm_sxs);
+                }
+                Ok(user_input) => {
+                    observer.observe(&ServerEvent::InputReceived {
+                        client,
+                        bytes: line.len() + 1,
+                    });
+                    // The main loop has ended, so there's no one left to pass frames to
+                    let event = Event::Client(client, ClientEvent::Frame(user_input));
+                    if stm_shl.send(event).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => match serde_json::from_str::<ControlFrame>(&line) {
+                    // Answered here, so that pings measure the network rather than the server
+                    Ok(ControlFrame::Ping(n)) => {
+                        send_to(client, ServerFrame::Pong(n), &shl_stm_sxs);
+                    }
+                    Ok(ControlFrame::Resync) => {
+                        let event = Event::Client(client, ClientEvent::Resync);
+                        if stm_shl.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => observer.observe(&ServerEvent::DecodeError {
+                        client,
+                        line,
+                        error: e.to_string(),
+                    }),
+                },
+            },
             Err(e) => {
-                panic!(
-                    "Stream in thread {:?} failed to read with error {}",
-                    thread::current().id(),
-                    e
-                );
+                // Don't panic, so that the main loop still hears about the disconnect
+                observer.observe(&ServerEvent::StreamError {
+                    client,
+                    error: format!("Failed to read: {}", e),
+                });
+                failed = true;
+                break;
             }
         }
     }
 
-    *alive.lock().unwrap() = false;
-    println!(
-        "Stream in {:?} is closed, marking closed and shutting down...",
-        thread::current().id()
-    );
+    // While shutting down, the writing thread goes on to write the responses to inputs already
+    // read, and stops once the server's main loop has ended
+    if !control.is_shutting_down() {
+        *alive.lock().unwrap() = false;
+        // Closes the writing thread's channel, so that it stops
+        shl_stm_sxs
+            .lock()
+            .expect("Poisoned map of outgoing sxs")
+            .remove(&client);
+    }
+    let _ = stm_shl.send(Event::Client(client, ClientEvent::Disconnected));
+    failed
 }
 
-fn relay_response_back<R>(mut stream: TcpStream, shl_stm_rx: Receiver<R>, alive: Arc<Mutex<bool>>)
+/// Writes frames to a client until its connection closes, or until there are none left to write,
+/// then closes it. Returns whether writing failed.
+fn relay_response_back<R>(
+    client: ClientId,
+    mut stream: TcpStream,
+    shl_stm_rx: Receiver<ServerFrame<R>>,
+    queued: Arc<AtomicUsize>,
+    alive: Arc<Mutex<bool>>,
+    observer: Arc<dyn ServerObserver>,
+) -> bool
 where
     R: Serialize + Send + 'static + Clone,
 {
-    println!(
-        "{:?} relaying responses to {}",
-        thread::current().id(),
-        stream.peer_addr().unwrap(),
-    );
-
+    let mut failed = false;
     while let Ok(output) = shl_stm_rx.recv() {
         {
             if !*alive.lock().unwrap() {
-                println!(
-                    "Stream in thread {:?} is closed, shutting down...",
-                    thread::current().id()
-                );
-                return;
+                break;
             }
         }
 
         let mut ser = serde_json::to_vec(&output).unwrap();
         ser.push(b'\n');
 
-        match stream.write(&ser) {
+        let written = stream.write_all(&ser);
+        queued.fetch_sub(1, Ordering::Relaxed);
+        match written {
+            Ok(()) => observer.observe(&ServerEvent::FrameSent {
+                client,
+                bytes: ser.len(),
+            }),
             Err(e) => {
-                panic!(
-                    "Stream in {:?} failed to write with error {}",
-                    thread::current().id(),
-                    e
-                );
+                observer.observe(&ServerEvent::StreamError {
+                    client,
+                    error: format!("Failed to write: {}", e),
+                });
+                failed = true;
+                break;
             }
-            Ok(_) => {}
         }
     }
 
-    println!(
-        "{:?} receiver closed, shutting down...",
-        thread::current().id()
-    );
+    let _ = stream.shutdown(Shutdown::Both);
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes as many milliseconds to process an input as it says.
+    struct Sleeper;
+
+    impl ShellServer<u64, u64> for Sleeper {
+        fn local_address(&self) -> String {
+            "127.0.0.1:0".to_owned()
+        }
+
+        fn process_input(&self, ms: u64) -> u64 {
+            thread::sleep(Duration::from_millis(ms));
+            ms
+        }
+    }
+
+    /// Panics on a zero input.
+    struct Panicker;
+
+    impl ShellServer<u64, u64> for Panicker {
+        fn local_address(&self) -> String {
+            "127.0.0.1:0".to_owned()
+        }
+
+        fn process_input(&self, n: u64) -> u64 {
+            assert!(n != 0, "zero input");
+            n
+        }
+    }
+
+    /// Runs `frames` from one client through a pool of two workers, returning what's relayed
+    /// back to the client.
+    fn run_pool<S>(server: S, order: ResponseOrder, frames: Vec<ClientFrame<u64>>) -> Vec<String>
+    where
+        S: ShellServer<u64, u64> + Send + Sync + 'static,
+    {
+        let observer: Arc<dyn ServerObserver> = Arc::new(|_: &ServerEvent| {});
+        let (sx, rx) = channel();
+        let senders = Arc::new(Mutex::new(HashMap::new()));
+        senders.lock().unwrap().insert(
+            ClientId(1),
+            ClientSender {
+                client: ClientId(1),
+                sx,
+                queued: Arc::new(AtomicUsize::new(0)),
+                observer: observer.clone(),
+            },
+        );
+
+        let (events_sx, events) = channel();
+        for frame in frames {
+            let _ = events_sx.send(Event::Client(ClientId(1), ClientEvent::Frame(frame)));
+        }
+        // Ends the pool once every input has been relayed
+        drop(events_sx);
+
+        let pool = WorkerPool::new(2).order(order);
+        let control = Arc::new(Control::default());
+        dispatch_to_worker_pool(pool, events, &senders, Arc::new(server), observer, control);
+
+        rx.try_iter()
+            .map(|frame| match frame {
+                ServerFrame::Broadcast(n) => format!("broadcast {}", n),
+                ServerFrame::Reply(RequestId(id), n) => format!("reply {} {}", id, n),
+                ServerFrame::Ack(seq) => format!("ack {}", seq),
+                _ => "other".to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn holds_back_responses_until_earlier_inputs_are_processed() {
+        let frames = vec![ClientFrame::Input(1, 100), ClientFrame::Input(2, 0)];
+        let relayed = run_pool(Sleeper, ResponseOrder::Input, frames);
+
+        assert_eq!(relayed, ["broadcast 100", "ack 1", "broadcast 0", "ack 2"]);
+    }
+
+    #[test]
+    fn relays_responses_as_they_complete() {
+        let frames = vec![
+            ClientFrame::Input(1, 100),
+            ClientFrame::Request(RequestId(7), 0),
+        ];
+        let relayed = run_pool(Sleeper, ResponseOrder::Completion, frames);
+
+        assert_eq!(relayed, ["reply 7 0", "broadcast 100", "ack 1"]);
+    }
+
+    #[test]
+    fn relays_inputs_after_one_that_panicked() {
+        let frames = vec![ClientFrame::Input(1, 0), ClientFrame::Input(2, 5)];
+        let relayed = run_pool(Panicker, ResponseOrder::Input, frames);
+
+        assert_eq!(relayed, ["broadcast 5", "ack 2"]);
+    }
+
+    #[test]
+    fn reports_a_client_as_slow_when_its_queue_fills_up() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let observed = events.clone();
+        let (sx, rx) = channel();
+        let sender = ClientSender {
+            client: ClientId(1),
+            sx,
+            queued: Arc::new(AtomicUsize::new(0)),
+            observer: Arc::new(move |event: &ServerEvent| {
+                observed.lock().unwrap().push(event.clone())
+            }),
+        };
+
+        for _ in 1..SLOW_CLIENT_QUEUE {
+            assert!(sender.send(ServerFrame::Broadcast(())));
+        }
+        assert!(events.lock().unwrap().is_empty());
+
+        sender.send(ServerFrame::Broadcast(()));
+        sender.send(ServerFrame::Broadcast(()));
+        let slow = ServerEvent::SlowClient {
+            client: ClientId(1),
+            queued: SLOW_CLIENT_QUEUE,
+        };
+        assert_eq!(*events.lock().unwrap(), vec![slow.clone()]);
+
+        // Reported again if the queue drains and fills back up
+        sender.queued.fetch_sub(2, Ordering::Relaxed);
+        sender.send(ServerFrame::Broadcast(()));
+        assert_eq!(*events.lock().unwrap(), vec![slow.clone(), slow]);
+
+        // Nothing's queued once the writing thread has gone
+        drop(rx);
+        assert!(!sender.send(ServerFrame::Broadcast(())));
+        assert_eq!(sender.queued.load(Ordering::Relaxed), SLOW_CLIENT_QUEUE);
+    }
 }
@@ -0,0 +1,982 @@
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chan;
+
+use client::{Event, Key, KeyAction, RequestError, ShellClient};
+use interpolation::InterpolationBuffer;
+use outbox::{Delivery, MessageId, PendingMessages};
+use prediction::Predictor;
+use protocol::{ClientFrame, ControlFrame, RequestId, ServerFrame};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json;
+use shell_connection::ShellConnection;
+use stats::{self, NetStats, StatsCollector};
+use sync::StateReplica;
+use timer::Timer;
+
+/// How often the UI is redrawn when interpolating, if the client has no frame interval of its own.
+const INTERPOLATION_FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Something a session scheduled on the event loop's timer.
+pub(crate) enum Timed {
+    RequestTimeout(RequestId),
+    DeferredSend(u64),
+    Reconnect,
+    Stats,
+}
+
+/// Something scheduled on the event loop's timer, which all of its sessions share.
+pub(crate) enum Due {
+    /// Due to the numbered session
+    Session(usize, Timed),
+    /// A redraw put off to keep to the client's `max_redraw_rate`
+    Redraw,
+}
+
+/// A frame read from a server, or why reading failed, tagged with the session it's for and which
+/// of the session's connections it was read from.
+pub(crate) type Received<R> = (usize, u64, Result<ServerFrame<R>, String>);
+
+/// An input to the client's event loop.
+pub(crate) enum UserInput {
+    Event(Event),
+    /// A line read from stdin, in line mode
+    Line(String),
+}
+
+/// Where a client's output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Output {
+    /// Drawn by the client itself, with its `draw` methods
+    Terminal,
+    /// Printed to stdout a line at a time, as the client's `response_line`s
+    Lines,
+    /// Nowhere, for clients running headless
+    Hidden,
+}
+
+/// What the client's event loop should do after handling an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Next {
+    Redraw,
+    SkipRedraw,
+    Exit,
+}
+
+/// Where a client's action is carried out.
+pub(crate) enum Routed<M: Serialize> {
+    /// On the numbered session
+    Session(usize, KeyAction<M>),
+    /// By the event loop alone, for actions that don't involve any server
+    Next(Next),
+}
+
+/// A client run by `run`, talking to one server or several, with a session for each.
+pub(crate) trait Frontend<M: Serialize, R> {
+    /// Calls `f` with the client, as the numbered session sees it.
+    fn with_session<T, F>(&mut self, session: usize, f: F) -> T
+    where
+        F: FnOnce(&mut dyn ShellClient<M, R>) -> T;
+
+    /// Given an input event, returns the action to take.
+    fn on_event(&mut self, event: Event) -> Routed<M>;
+
+    /// In line mode, given a line of input, returns the action to take, if the client handles
+    /// whole lines.
+    fn on_line(&mut self, line: &str) -> Option<Routed<M>>;
+
+    /// Called once the numbered session's connection is lost for good.
+    fn on_closed(&mut self, _session: usize) {}
+
+    /// Called once the screen has been cleared from under the client's UI, before it's redrawn.
+    fn on_invalidate(&mut self) {}
+
+    fn on_resize(&mut self, cols: u16, rows: u16);
+    fn on_tick(&mut self, dt: Duration);
+    fn frame_interval(&self) -> Option<Duration>;
+    fn max_redraw_rate(&self) -> Option<u32>;
+    fn first_draw(&mut self);
+    fn draw(&mut self);
+    fn last_draw(&mut self);
+}
+
+/// A `ShellClient`, talking to its one server.
+pub(crate) struct Single<C>(pub C);
+
+impl<M, R, C> Frontend<M, R> for Single<C>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send,
+    C: ShellClient<M, R>,
+{
+    fn with_session<T, F>(&mut self, _session: usize, f: F) -> T
+    where
+        F: FnOnce(&mut dyn ShellClient<M, R>) -> T,
+    {
+        f(&mut self.0)
+    }
+
+    fn on_event(&mut self, event: Event) -> Routed<M> {
+        Routed::Session(0, self.0.on_event(event))
+    }
+
+    fn on_line(&mut self, line: &str) -> Option<Routed<M>> {
+        self.0
+            .on_line(line)
+            .map(|action| Routed::Session(0, action))
+    }
+
+    fn on_invalidate(&mut self) {
+        self.0.on_invalidate();
+    }
+
+    fn on_resize(&mut self, cols: u16, rows: u16) {
+        self.0.on_resize(cols, rows);
+    }
+
+    fn on_tick(&mut self, dt: Duration) {
+        self.0.on_tick(dt);
+    }
+
+    fn frame_interval(&self) -> Option<Duration> {
+        self.0.frame_interval()
+    }
+
+    fn max_redraw_rate(&self) -> Option<u32> {
+        self.0.max_redraw_rate()
+    }
+
+    fn first_draw(&mut self) {
+        self.0.first_draw();
+    }
+
+    fn draw(&mut self) {
+        self.0.draw();
+    }
+
+    fn last_draw(&mut self) {
+        self.0.last_draw();
+    }
+}
+
+/// The client-side state of a connection to a server: everything the event loop needs to carry
+/// out a client's `KeyAction`s and hand it what the server sends.
+pub(crate) struct ClientSession<M, R> {
+    // Which of the event loop's sessions this is
+    index: usize,
+    // None while reconnecting, which only clients with an outbox do
+    connection: Option<ShellConnection>,
+    connection_number: u64,
+    url: String,
+    response_tx: chan::Sender<Received<R>>,
+    outbox: Option<PendingMessages>,
+    reconnect_attempts: u32,
+    closed: bool,
+    output: Output,
+    // Synced server state, and the inputs predicted on top of it
+    replica: StateReplica,
+    predictor: Predictor<M>,
+    next_seq: u64,
+    // Inputs sent or queued that the server hasn't acknowledged yet
+    unacked: HashSet<u64>,
+    // The lockstep turn that started last, which inputs are sent for by default
+    turn: Option<u64>,
+    interpolator: Option<InterpolationBuffer<R>>,
+    // Requests awaiting replies, and messages awaiting their send time
+    timer: Timer<Due>,
+    pending_requests: HashSet<RequestId>,
+    deferred: HashMap<u64, M>,
+    next_deferred: u64,
+    // Only sampled for clients with a debug overlay, or that collect statistics themselves
+    stats: StatsCollector,
+    collect_stats: bool,
+    overlay_key: Option<Key>,
+    overlay_visible: bool,
+}
+
+impl<M, R> ClientSession<M, R>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+{
+    /// Returns the numbered session. What it schedules on `timer` is to be passed back to `fire`,
+    /// and the frames it sends on `response_tx` to `receive`.
+    pub fn new<C>(
+        index: usize,
+        connection: ShellConnection,
+        client: &C,
+        output: Output,
+        timer: Timer<Due>,
+        response_tx: chan::Sender<Received<R>>,
+    ) -> Self
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        read_frames(&connection, index, 0, response_tx.clone());
+        let stats = StatsCollector::new(connection.counters());
+
+        let mut session = Self {
+            index,
+            connection: Some(connection),
+            connection_number: 0,
+            url: client.server_url(),
+            response_tx,
+            outbox: client.outbox().map(PendingMessages::new),
+            reconnect_attempts: 0,
+            closed: false,
+            output,
+            replica: StateReplica::default(),
+            predictor: Predictor::new(),
+            next_seq: 0,
+            unacked: HashSet::new(),
+            turn: None,
+            interpolator: client.interpolation().map(InterpolationBuffer::new),
+            timer,
+            pending_requests: HashSet::new(),
+            deferred: HashMap::new(),
+            next_deferred: 0,
+            stats,
+            collect_stats: client.collect_stats(),
+            overlay_key: client.debug_overlay_key(),
+            overlay_visible: false,
+        };
+        if let Some(ref mut outbox) = session.outbox {
+            session.unacked.extend(outbox.load(&mut session.next_seq));
+        }
+        if session.overlay_key.is_some() || session.collect_stats {
+            session.schedule(stats::SAMPLE_INTERVAL, Timed::Stats);
+        }
+
+        session
+    }
+
+    fn schedule(&self, delay: Duration, timed: Timed) {
+        self.timer.schedule(delay, Due::Session(self.index, timed));
+    }
+
+    /// Sends any inputs left in the client's outbox by a previous run.
+    pub fn start<C>(&mut self, client: &mut C)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        self.flush(client);
+    }
+
+    pub fn is_interpolating(&self) -> bool {
+        self.interpolator.is_some()
+    }
+
+    pub fn is_overlay_key(&self, key: &Key) -> bool {
+        self.overlay_key.as_ref() == Some(key)
+    }
+
+    pub fn set_overlay_visible(&mut self, visible: bool) {
+        self.overlay_visible = visible;
+    }
+
+    pub fn overlay_visible(&self) -> bool {
+        self.overlay_visible
+    }
+
+    /// Whether the UI shows the statistics, and so needs redrawing when they're sampled.
+    pub fn shows_stats(&self) -> bool {
+        self.overlay_visible || self.collect_stats
+    }
+
+    pub fn stats(&self) -> &NetStats {
+        self.stats.stats()
+    }
+
+    /// Whether the connection to the server has been lost for good.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether the server has acknowledged every input sent, and answered every request, with
+    /// nothing waiting to be sent later.
+    pub fn is_settled(&self) -> bool {
+        self.unacked.is_empty() && self.pending_requests.is_empty() && self.deferred.is_empty()
+    }
+
+    /// Carries out an action returned by the client.
+    pub fn perform<C>(&mut self, client: &mut C, action: KeyAction<M>) -> Next
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        match action {
+            KeyAction::DoNothing | KeyAction::Redraw => {}
+            KeyAction::SkipRedraw => return Next::SkipRedraw,
+            KeyAction::Exit => return Next::Exit,
+            KeyAction::SendMessage(msg) => self.send(client, msg, None),
+            KeyAction::SendForTurn(turn, msg) => self.send(client, msg, Some(turn)),
+            KeyAction::SendMany(msgs) => {
+                for msg in msgs {
+                    self.send(client, msg, None);
+                }
+            }
+            KeyAction::SendAfter(delay, msg) => {
+                self.next_deferred += 1;
+                self.deferred.insert(self.next_deferred, msg);
+                self.schedule(delay, Timed::DeferredSend(self.next_deferred));
+            }
+            KeyAction::ExitWithMessage(msg) => {
+                self.send(client, msg, None);
+                return Next::Exit;
+            }
+            KeyAction::Request(request) => {
+                // Only clients with an outbox stay running while reconnecting
+                if self.connection.is_none() {
+                    client.receive_reply(request.id, Err(RequestError::Disconnected));
+                    return Next::Redraw;
+                }
+
+                if let Some(timeout) = request.timeout {
+                    self.schedule(timeout, Timed::RequestTimeout(request.id));
+                }
+                self.pending_requests.insert(request.id);
+                let sent = self.connection.as_mut().map(|connection| {
+                    connection.send_frame(ClientFrame::Request(request.id, request.message))
+                });
+                if let Some(Err(_)) = sent {
+                    self.disconnect(client);
+                }
+            }
+            KeyAction::Cancel(id) => {
+                self.pending_requests.remove(&id);
+            }
+        }
+
+        Next::Redraw
+    }
+
+    /// Sends an input for the given lockstep turn, or if none is given, the turn that started last.
+    fn send<C>(&mut self, client: &mut C, msg: M, turn: Option<u64>)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        self.next_seq += 1;
+        let seq = self.next_seq;
+        let turn = turn.or(self.turn);
+        match self.outbox {
+            None => {
+                let sent = self
+                    .connection
+                    .as_mut()
+                    .map(|connection| connection.send_frame(ClientFrame::input(seq, turn, &msg)));
+                if let Some(Err(_)) = sent {
+                    self.disconnect(client);
+                }
+                self.unacked.insert(seq);
+            }
+            Some(ref mut outbox) => {
+                if !outbox.push(seq, turn, serde_json::to_value(&msg).unwrap()) {
+                    client.on_delivery(MessageId(seq), Delivery::Dropped);
+                    return;
+                }
+                self.unacked.insert(seq);
+                let delivery = if self.send_held(client, seq) {
+                    Delivery::Sent
+                } else {
+                    Delivery::Queued
+                };
+                client.on_delivery(MessageId(seq), delivery);
+            }
+        }
+
+        let predicted = self.predictor.push(
+            self.next_seq,
+            msg,
+            self.replica.current(),
+            |state, input| client.predict(state, input),
+        );
+        if let (Some(state), None) = (predicted, self.interpolator.as_ref()) {
+            client.on_state(&state);
+        }
+    }
+
+    /// Writes an input held in the outbox to the connection, if there is one. Returns whether it
+    /// was written.
+    fn send_held<C>(&mut self, client: &mut C, seq: u64) -> bool
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        let message = self.outbox.as_ref().and_then(|outbox| outbox.get(seq));
+        let written = match (self.connection.as_mut(), message) {
+            (Some(connection), Some((turn, message))) => connection
+                .send_frame(ClientFrame::input(seq, turn, message))
+                .is_ok(),
+            _ => return false,
+        };
+
+        match self.outbox {
+            Some(ref mut outbox) if written => outbox.mark_sent(seq),
+            _ => self.disconnect(client),
+        }
+        written
+    }
+
+    /// Sends every input held in the outbox, in order, including any sent before the connection
+    /// was lost but never acknowledged.
+    fn flush<C>(&mut self, client: &mut C)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        let held = self.outbox.as_ref().map(|o| o.held()).unwrap_or_default();
+        for (seq, already_sent) in held {
+            if !self.send_held(client, seq) {
+                break;
+            }
+            if !already_sent {
+                client.on_delivery(MessageId(seq), Delivery::Sent);
+            }
+        }
+    }
+
+    /// Forgets an input the server has answered, reporting it `Acknowledged` or `Rejected`.
+    fn acknowledge<C>(&mut self, client: &mut C, ack: u64, delivery: Delivery)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        self.unacked.remove(&ack);
+        let acknowledged = self
+            .outbox
+            .as_mut()
+            .is_some_and(|outbox| outbox.acknowledge(ack));
+        if acknowledged {
+            client.on_delivery(MessageId(ack), delivery);
+        }
+    }
+
+    /// Handles the connection to the server being lost: if the client has an outbox, reconnects
+    /// in the background, and otherwise closes the session.
+    fn disconnect<C>(&mut self, client: &mut C)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        if self.connection.take().is_none() {
+            return;
+        }
+
+        warn!("Lost the connection to {}", self.url);
+
+        // Replies only come back over the connection their request was sent on
+        let mut failed: Vec<_> = self.pending_requests.drain().collect();
+        failed.sort();
+        for id in failed {
+            client.receive_reply(id, Err(RequestError::Disconnected));
+        }
+
+        match self.outbox {
+            Some(ref outbox) => {
+                self.reconnect_attempts = 0;
+                self.schedule(outbox.retry_interval(0), Timed::Reconnect);
+                client.on_connection(false);
+            }
+            None => self.closed = true,
+        }
+    }
+
+    fn reconnect<C>(&mut self, client: &mut C)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        match ShellConnection::connect(&self.url) {
+            Ok(mut connection) => {
+                info!("Reconnected to {}", self.url);
+                connection.set_counters(self.stats.counters());
+                self.stats.reconnected();
+                self.connection_number += 1;
+                read_frames(
+                    &connection,
+                    self.index,
+                    self.connection_number,
+                    self.response_tx.clone(),
+                );
+                self.connection = Some(connection);
+                // The server starts over with a snapshot
+                self.replica = StateReplica::default();
+
+                client.on_connection(true);
+                self.flush(client);
+            }
+            Err(e) => {
+                debug!("Failed to reconnect to {}: {:?}", self.url, e);
+                self.reconnect_attempts += 1;
+                let delay = self
+                    .outbox
+                    .as_ref()
+                    .map(|outbox| outbox.retry_interval(self.reconnect_attempts));
+                if let Some(delay) = delay {
+                    self.schedule(delay, Timed::Reconnect);
+                }
+            }
+        }
+    }
+
+    /// Hands the client a frame received from the server, or handles the connection being lost.
+    pub fn receive<C>(&mut self, client: &mut C, received: Received<R>)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        let frame = match received {
+            (_, number, _) if number != self.connection_number => return,
+            (_, _, Ok(frame)) => frame,
+            (_, _, Err(e)) => {
+                warn!("Failed to read from {}: {}", self.url, e);
+                self.stats.error(e);
+                return self.disconnect(client);
+            }
+        };
+
+        match frame {
+            ServerFrame::Broadcast(response) => {
+                self.print_line(client, &response);
+                client.receive_response(response);
+            }
+            ServerFrame::Reply(id, response) => {
+                if self.pending_requests.remove(&id) {
+                    self.print_line(client, &response);
+                    client.receive_reply(id, Ok(response));
+                }
+            }
+            ServerFrame::Refused(id) => {
+                if self.pending_requests.remove(&id) {
+                    client.receive_reply(id, Err(RequestError::Refused));
+                }
+            }
+            ServerFrame::TurnStart(turn) => {
+                self.turn = Some(turn.number);
+                client.on_turn_start(turn);
+            }
+            ServerFrame::State {
+                version,
+                update,
+                ack,
+                time,
+            } => {
+                if let Some(ack) = ack {
+                    self.acknowledge(client, ack, Delivery::Acknowledged);
+                }
+
+                let predictor = &mut self.predictor;
+                let state = self
+                    .replica
+                    .apply(version, update)
+                    .and_then(|authoritative| {
+                        predictor.reconcile(ack, authoritative, |state, input| {
+                            client.predict(state, input)
+                        })
+                    });
+                match (state, self.interpolator.as_mut()) {
+                    (Some(state), Some(interpolator)) => interpolator.push(time, state),
+                    (Some(state), None) => client.on_state(&state),
+                    (None, _) => {}
+                }
+
+                // Rather than waiting for the server's next snapshot
+                if self.replica.should_resync() {
+                    let sent = self
+                        .connection
+                        .as_mut()
+                        .map(|connection| connection.send_control(ControlFrame::Resync));
+                    if let Some(Err(_)) = sent {
+                        self.disconnect(client);
+                    }
+                }
+            }
+            ServerFrame::Ack(seq) => self.answered(client, seq, Delivery::Acknowledged),
+            ServerFrame::Rejected(seq) => self.answered(client, seq, Delivery::Rejected),
+            ServerFrame::Pong(n) => self.stats.pong(n),
+        }
+    }
+
+    /// Handles the server answering an input: forgets it, and stops predicting it.
+    fn answered<C>(&mut self, client: &mut C, seq: u64, delivery: Delivery)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        self.acknowledge(client, seq, delivery);
+
+        let predictor = &mut self.predictor;
+        let state = self.replica.current().and_then(|authoritative| {
+            predictor.reconcile(Some(seq), authoritative, |state, input| {
+                client.predict(state, input)
+            })
+        });
+        if let (Some(state), None) = (state, self.interpolator.as_ref()) {
+            client.on_state(&state);
+        }
+    }
+
+    fn print_line<C>(&self, client: &C, response: &R)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        if self.output == Output::Lines {
+            if let Some(line) = client.response_line(response) {
+                println!("{}", line);
+            }
+        }
+    }
+
+    /// Handles something scheduled on the session's timer coming due.
+    pub fn fire<C>(&mut self, client: &mut C, timed: Timed)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        match timed {
+            Timed::RequestTimeout(id) => {
+                if self.pending_requests.remove(&id) {
+                    debug!("Request {:?} timed out", id);
+                    client.receive_reply(id, Err(RequestError::TimedOut));
+                }
+            }
+            Timed::DeferredSend(id) => {
+                if let Some(msg) = self.deferred.remove(&id) {
+                    self.send(client, msg, None);
+                }
+            }
+            Timed::Reconnect => self.reconnect(client),
+            Timed::Stats => {
+                let queued = self.outbox.as_ref().map_or(0, |o| o.held().len());
+                self.stats.sample(queued);
+                if self.collect_stats {
+                    client.on_stats(self.stats.stats());
+                }
+
+                let ping = self.stats.ping();
+                let sent = self
+                    .connection
+                    .as_mut()
+                    .map(|connection| connection.send_control(ControlFrame::Ping(ping)));
+                if let Some(Err(_)) = sent {
+                    self.disconnect(client);
+                }
+                self.schedule(stats::SAMPLE_INTERVAL, Timed::Stats);
+            }
+        }
+    }
+
+    /// Hands the client the synced state as of this frame, if it's interpolating.
+    pub fn sample<C>(&mut self, client: &mut C)
+    where
+        C: ShellClient<M, R> + ?Sized,
+    {
+        if let Some(state) = self.interpolator.as_mut().and_then(|i| i.sample()) {
+            client.on_state(&state);
+        }
+    }
+}
+
+/// Runs the client's event loop until it exits or loses every connection for good: hands the
+/// client its input events, terminal resizes and whatever its servers send, and carries out the
+/// actions it returns, then any actions `queued` has for its first session. The client only draws
+/// itself if its `output` is the terminal.
+///
+/// Frames that arrive together are all handed to the client before it's redrawn once, and it is
+/// redrawn no more often than its `max_redraw_rate`. If a session has a `debug_overlay_key`, that
+/// key is kept from the client and shows or hides the session's network statistics on top of its
+/// UI, in place of any other session's.
+///
+/// The loop also ends when `input_rx` closes, except in line mode, where it first waits for the
+/// servers to acknowledge every input and answer every request sent, or for the connections to
+/// close.
+pub(crate) fn run<C, M, R, Q>(
+    connections: Vec<ShellConnection>,
+    mut client: C,
+    mut input_rx: chan::Receiver<UserInput>,
+    resize_rx: chan::Receiver<(u16, u16)>,
+    output: Output,
+    mut queued: Q,
+) where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: Frontend<M, R>,
+    Q: FnMut(&mut C) -> Option<KeyAction<M>>,
+{
+    let (timer, timer_rx) = Timer::new();
+    let (response_tx, response_rx) = chan::async();
+    let mut sessions = Vec::new();
+    for (index, connection) in connections.into_iter().enumerate() {
+        let session = client.with_session(index, |client| {
+            ClientSession::new(
+                index,
+                connection,
+                &*client,
+                output,
+                timer.clone(),
+                response_tx.clone(),
+            )
+        });
+        sessions.push(session);
+    }
+    let mut closed = vec![false; sessions.len()];
+    let draw = output == Output::Terminal;
+
+    // Frame timer; a zero duration never ticks
+    let interpolating = sessions.iter().any(|session| session.is_interpolating());
+    let frame_interval = client.frame_interval().or(if interpolating {
+        Some(INTERPOLATION_FRAME_INTERVAL)
+    } else {
+        None
+    });
+    let frame_rx = chan::tick(frame_interval.unwrap_or_default());
+    let mut last_tick = Instant::now();
+
+    let min_redraw_interval = client
+        .max_redraw_rate()
+        .map(|rate| Duration::from_secs(1) / rate.max(1))
+        .unwrap_or_default();
+    let mut last_draw = Instant::now();
+    let mut redraw_scheduled = false;
+
+    // Stands in for the input once it closes in line mode, since a closed channel would spin
+    let (_no_input_tx, no_input_rx) = chan::sync(0);
+    let mut input_closed = false;
+
+    if draw {
+        client.first_draw();
+    }
+    for (index, session) in sessions.iter_mut().enumerate() {
+        client.with_session(index, |client| session.start(client));
+    }
+
+    // Performs any actions queued up before the first event, without redrawing
+    let mut next = Next::SkipRedraw;
+    loop {
+        while next != Next::Exit {
+            match queued(&mut client) {
+                Some(action) => {
+                    let routed = Routed::Session(0, action);
+                    if perform(&mut sessions, &mut client, routed) == Next::Exit {
+                        next = Next::Exit;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        match next {
+            Next::Redraw if draw => {
+                let since_draw = last_draw.elapsed();
+                if since_draw >= min_redraw_interval {
+                    client.draw();
+                    if let Some(session) = sessions.iter().find(|s| s.overlay_visible()) {
+                        let _ = stats::draw_overlay(session.stats());
+                    }
+                    last_draw = Instant::now();
+                } else if !redraw_scheduled {
+                    timer.schedule(min_redraw_interval - since_draw, Due::Redraw);
+                    redraw_scheduled = true;
+                }
+            }
+            Next::Redraw | Next::SkipRedraw => {}
+            Next::Exit => break,
+        }
+
+        next = Next::Redraw;
+        let mut more_frames = false;
+
+        chan_select! {
+            input_rx.recv() -> event => {
+                match event {
+                    Some(UserInput::Event(event)) => match overlay_for(&sessions, &event) {
+                        Some(index) => {
+                            let shown = !sessions[index].overlay_visible();
+                            for (other, session) in sessions.iter_mut().enumerate() {
+                                session.set_overlay_visible(shown && other == index);
+                            }
+                            // Has the client redraw everything, to cover up the hidden overlay
+                            if draw && !shown {
+                                let _ = stats::clear_overlay();
+                                client.on_invalidate();
+                            }
+                        }
+                        None => {
+                            let routed = client.on_event(event);
+                            next = perform(&mut sessions, &mut client, routed);
+                        }
+                    },
+                    Some(UserInput::Line(line)) => {
+                        next = perform_line(&mut sessions, &mut client, &line);
+                    }
+                    None if output == Output::Lines => input_closed = true,
+                    None => next = Next::Exit,
+                }
+            },
+            response_rx.recv() -> frame => {
+                match frame {
+                    Some(received) => {
+                        receive(&mut sessions, &mut client, received);
+                        more_frames = true;
+                    }
+                    None => next = Next::Exit,
+                }
+            },
+            resize_rx.recv() -> size => {
+                let (cols, rows) = size.unwrap();
+                client.on_resize(cols, rows);
+            },
+            frame_rx.recv() => {
+                let now = Instant::now();
+                client.on_tick(now - last_tick);
+                last_tick = now;
+                for (index, session) in sessions.iter_mut().enumerate() {
+                    client.with_session(index, |client| session.sample(client));
+                }
+            },
+            timer_rx.recv() -> due => {
+                match due.unwrap() {
+                    Due::Redraw => redraw_scheduled = false,
+                    Due::Session(index, timed) => {
+                        let session = &mut sessions[index];
+                        if let Timed::Stats = timed {
+                            if !session.shows_stats() {
+                                next = Next::SkipRedraw;
+                            }
+                        }
+                        client.with_session(index, |client| session.fire(client, timed));
+                    }
+                }
+            },
+        }
+
+        // Hands over every other frame that's already arrived, before redrawing once
+        while more_frames {
+            match try_recv(&response_rx) {
+                Some(Some(received)) => receive(&mut sessions, &mut client, received),
+                Some(None) => {
+                    next = Next::Exit;
+                    more_frames = false;
+                }
+                None => more_frames = false,
+            }
+        }
+
+        for (index, session) in sessions.iter().enumerate() {
+            if session.is_closed() && !closed[index] {
+                closed[index] = true;
+                client.on_closed(index);
+            }
+        }
+        if closed.iter().all(|&closed| closed) {
+            next = Next::Exit;
+        }
+
+        if input_closed {
+            input_rx = no_input_rx.clone();
+            let settled = sessions
+                .iter()
+                .all(|session| session.is_closed() || session.is_settled());
+            if settled {
+                next = Next::Exit;
+            }
+        }
+    }
+
+    if draw {
+        client.last_draw();
+    }
+}
+
+/// Returns the first session whose `debug_overlay_key` the event is a press of, if any.
+fn overlay_for<M, R>(sessions: &[ClientSession<M, R>], event: &Event) -> Option<usize>
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+{
+    match *event {
+        Event::Key(ref key) => sessions.iter().position(|s| s.is_overlay_key(key)),
+        _ => None,
+    }
+}
+
+/// Carries out an action returned by the client, on the session it's for.
+fn perform<C, M, R>(sessions: &mut [ClientSession<M, R>], client: &mut C, routed: Routed<M>) -> Next
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: Frontend<M, R>,
+{
+    match routed {
+        Routed::Session(index, action) => {
+            let session = &mut sessions[index];
+            client.with_session(index, |client| session.perform(client, action))
+        }
+        Routed::Next(next) => next,
+    }
+}
+
+/// Carries out the action the client returns for a line of input, or if it doesn't handle whole
+/// lines, those it returns for each character of the line and then Enter.
+fn perform_line<C, M, R>(sessions: &mut [ClientSession<M, R>], client: &mut C, line: &str) -> Next
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: Frontend<M, R>,
+{
+    if let Some(routed) = client.on_line(line) {
+        return perform(sessions, client, routed);
+    }
+
+    let mut next = Next::Redraw;
+    for c in line.chars().chain(iter::once('\n')) {
+        let routed = client.on_event(Event::Key(Key::Char(c)));
+        next = perform(sessions, client, routed);
+        if next == Next::Exit {
+            break;
+        }
+    }
+    next
+}
+
+/// Hands a frame received from a server to the session it's for.
+fn receive<C, M, R>(sessions: &mut [ClientSession<M, R>], client: &mut C, received: Received<R>)
+where
+    M: Serialize,
+    R: DeserializeOwned + Send + 'static,
+    C: Frontend<M, R>,
+{
+    let index = received.0;
+    let session = &mut sessions[index];
+    client.with_session(index, |client| session.receive(client, received));
+}
+
+/// Reads frames from the server on their own thread, tagged with the session's `index` and the
+/// connection's `number`, until reading fails.
+fn read_frames<R>(
+    connection: &ShellConnection,
+    index: usize,
+    number: u64,
+    response_tx: chan::Sender<Received<R>>,
+) where
+    R: DeserializeOwned + Send + 'static,
+{
+    let mut read_connection = match connection.try_clone() {
+        Ok(read_connection) => read_connection,
+        Err(e) => return response_tx.send((index, number, Err(format!("Error reading: {:?}", e)))),
+    };
+
+    thread::spawn(move || loop {
+        let frame = read_connection.read_frame();
+        let failed = frame.is_err();
+        response_tx.send((index, number, frame));
+        if failed {
+            break;
+        }
+    });
+}
+
+/// Returns whatever `rx` has waiting, without blocking: `None` if nothing is, and `Some(None)` if
+/// it's closed.
+pub(crate) fn try_recv<T>(rx: &chan::Receiver<T>) -> Option<Option<T>> {
+    let mut received = None;
+    chan_select! {
+        default => {},
+        rx.recv() -> value => received = Some(value),
+    }
+    received
+}
@@ -1,13 +1,21 @@
 use std::io::{self, BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
 
 use serde_json;
 
 use serde::{Serialize, de::DeserializeOwned};
 
+use protocol::{ClientFrame, ControlFrame, ServerFrame};
+use stats::Counters;
+
 pub(crate) struct ShellConnection {
     stream: TcpStream,
+    // Kept across reads, so that bytes buffered past the end of one frame aren't lost
+    reader: BufReader<TcpStream>,
     remote_url: String,
+    // Shared with clones, so that reading and writing halves count into the same totals
+    counters: Arc<Counters>,
 }
 
 impl ShellConnection {
@@ -15,8 +23,10 @@ impl ShellConnection {
         let stream = TcpStream::connect(url)?;
 
         Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
             stream,
             remote_url: url.to_owned(),
+            counters: Arc::new(Counters::default()),
         })
     }
 
@@ -24,24 +34,57 @@ impl ShellConnection {
         let stream_clone = self.stream.try_clone()?;
 
         Ok(Self {
+            reader: BufReader::new(stream_clone.try_clone()?),
             stream: stream_clone,
             remote_url: self.remote_url.clone(),
+            counters: self.counters.clone(),
         })
     }
 
-    pub fn send_input<M: Serialize>(&mut self, msg: M) -> io::Result<usize> {
-        let mut sendable = serde_json::to_vec(&msg).unwrap();
+    /// Counts this connection's traffic into `counters` rather than its own, so that the totals
+    /// carry on across reconnects. Only affects clones made afterwards.
+    pub fn set_counters(&mut self, counters: Arc<Counters>) {
+        self.counters = counters;
+    }
+
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    pub fn send_frame<M: Serialize>(&mut self, frame: ClientFrame<M>) -> io::Result<()> {
+        self.write_line(&frame)
+    }
+
+    pub fn send_control(&mut self, frame: ControlFrame) -> io::Result<()> {
+        self.write_line(&frame)
+    }
+
+    fn write_line<T: Serialize>(&mut self, frame: &T) -> io::Result<()> {
+        let mut sendable = serde_json::to_vec(frame).unwrap();
         sendable.push(b'\n');
 
-        self.stream.write(&sendable)
+        self.stream.write_all(&sendable)?;
+        self.counters.sent(sendable.len());
+        Ok(())
     }
 
-    pub fn read_response<R: DeserializeOwned>(&self) -> Result<R, String> {
+    pub fn read_frame<R: DeserializeOwned>(&mut self) -> Result<ServerFrame<R>, String> {
+        let resp = self.read_line()?;
+        serde_json::from_str(&resp).map_err(|e| format!("Error reading: {:?}", e))
+    }
+
+    /// Reads the next frame without decoding it. Errors only if reading fails or the connection
+    /// closes.
+    pub fn read_line(&mut self) -> Result<String, String> {
         let mut resp = String::new();
-        BufReader::new(&self.stream)
+        let read = self
+            .reader
             .read_line(&mut resp)
             .map_err(|e| format!("Error reading: {:?}", e))?;
-
-        serde_json::from_str(&resp).map_err(|e| format!("Error reading: {:?}", e))
+        if read == 0 {
+            return Err("Connection closed".to_owned());
+        }
+        self.counters.received(read);
+        Ok(resp)
     }
 }
@@ -0,0 +1,268 @@
+//! Network statistics collected by the client runtime, and the debug overlay that shows them.
+//!
+//! The overlay is opt-in: clients turn it on by returning a key from
+//! `ShellClient::debug_overlay_key`, and pressing that key shows or hides it on top of whatever
+//! the client draws.
+//!
+//! Clients can also have the statistics handed to `ShellClient::on_stats`, by returning `true` from
+//! `ShellClient::collect_stats`.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use termion::{clear, cursor, style};
+
+/// How often the statistics are sampled, and the server pinged.
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The width of the overlay, including its border.
+const OVERLAY_WIDTH: u16 = 34;
+
+/// A snapshot of a client's connection to the server.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetStats {
+    /// The round-trip time of the last answered ping
+    pub rtt: Option<Duration>,
+    /// Frames received from the server per second
+    pub messages_in: f64,
+    /// Frames sent to the server per second
+    pub messages_out: f64,
+    /// Bytes received from the server per second
+    pub bytes_in: f64,
+    /// Bytes sent to the server per second
+    pub bytes_out: f64,
+    /// Inputs held in the outbox, waiting to be acknowledged
+    pub queued: usize,
+    /// How many times the connection has been re-established
+    pub reconnects: u32,
+    /// Why the connection was last lost, or a frame couldn't be read
+    pub last_error: Option<String>,
+}
+
+/// Running totals of the traffic over a connection, shared between its reading and writing
+/// halves, and kept across reconnects.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Counters {
+    pub fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn totals(&self) -> [u64; 4] {
+        [
+            self.messages_in.load(Ordering::Relaxed),
+            self.messages_out.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// Turns a connection's running totals into rates, and times pings to the server.
+pub(crate) struct StatsCollector {
+    counters: Arc<Counters>,
+    last_totals: [u64; 4],
+    last_sample: Instant,
+    next_ping: u64,
+    // The last ping sent, and when
+    ping: Option<(u64, Instant)>,
+    stats: NetStats,
+}
+
+impl StatsCollector {
+    pub fn new(counters: Arc<Counters>) -> Self {
+        Self {
+            last_totals: counters.totals(),
+            counters,
+            last_sample: Instant::now(),
+            next_ping: 0,
+            ping: None,
+            stats: NetStats::default(),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    pub fn stats(&self) -> &NetStats {
+        &self.stats
+    }
+
+    /// Returns the number of a new ping, timed from now.
+    pub fn ping(&mut self) -> u64 {
+        self.next_ping += 1;
+        self.ping = Some((self.next_ping, Instant::now()));
+        self.next_ping
+    }
+
+    /// Records the answer to a ping, if it's the latest one.
+    pub fn pong(&mut self, n: u64) {
+        if let Some((sent, at)) = self.ping {
+            if sent == n {
+                self.stats.rtt = Some(at.elapsed());
+                self.ping = None;
+            }
+        }
+    }
+
+    pub fn reconnected(&mut self) {
+        self.stats.reconnects += 1;
+    }
+
+    pub fn error(&mut self, error: String) {
+        self.stats.last_error = Some(error);
+    }
+
+    /// Updates the rates with the traffic since the last sample.
+    pub fn sample(&mut self, queued: usize) {
+        let totals = self.counters.totals();
+        let secs = self.last_sample.elapsed().as_secs_f64().max(0.001);
+        let last = self.last_totals;
+        let rate = |i: usize| (totals[i] - last[i]) as f64 / secs;
+
+        self.stats.messages_in = rate(0);
+        self.stats.messages_out = rate(1);
+        self.stats.bytes_in = rate(2);
+        self.stats.bytes_out = rate(3);
+        self.stats.queued = queued;
+
+        self.last_totals = totals;
+        self.last_sample = Instant::now();
+    }
+}
+
+/// Draws the statistics in a box in the top right corner of the terminal, on top of whatever
+/// the client has drawn.
+pub(crate) fn draw_overlay(stats: &NetStats) -> io::Result<()> {
+    let (cols, _) = termion::terminal_size()?;
+    let inner = OVERLAY_WIDTH as usize - 2;
+    let left = cols.saturating_sub(OVERLAY_WIDTH) + 1;
+
+    let rtt = match stats.rtt {
+        Some(rtt) => format!("{:.1} ms", rtt.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    };
+    let mut error = stats.last_error.clone().unwrap_or_else(|| "-".to_owned());
+    if error.chars().count() > inner - 7 {
+        error = error.chars().take(inner - 8).chain(Some('…')).collect();
+    }
+    let lines = [
+        format!("rtt    {}", rtt),
+        format!(
+            "msg/s  {:.0} in, {:.0} out",
+            stats.messages_in, stats.messages_out
+        ),
+        format!(
+            "B/s    {:.0} in, {:.0} out",
+            stats.bytes_in, stats.bytes_out
+        ),
+        format!("queued {}", stats.queued),
+        format!("reconn {}", stats.reconnects),
+        format!("error  {}", error),
+    ];
+
+    let mut stdout = io::stdout();
+    let border = "─".repeat(inner);
+    write!(stdout, "{}{}", cursor::Save, style::Reset)?;
+    write!(stdout, "{}┌{}┐", cursor::Goto(left, 1), border)?;
+    for (i, line) in lines.iter().enumerate() {
+        let line: String = line.chars().take(inner).collect();
+        write!(
+            stdout,
+            "{}│{:width$}│",
+            cursor::Goto(left, i as u16 + 2),
+            line,
+            width = inner
+        )?;
+    }
+    write!(
+        stdout,
+        "{}└{}┘{}",
+        cursor::Goto(left, lines.len() as u16 + 2),
+        border,
+        cursor::Restore
+    )?;
+    stdout.flush()
+}
+
+/// Blanks the area the overlay was drawn over, before the client is told to redraw everything.
+pub(crate) fn clear_overlay() -> io::Result<()> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", clear::All)?;
+    stdout.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn turns_the_traffic_since_the_last_sample_into_rates() {
+        let counters = Arc::new(Counters::default());
+        // Traffic before the collector starts isn't counted
+        counters.received(1000);
+        let mut collector = StatsCollector::new(counters.clone());
+
+        counters.received(10);
+        counters.received(30);
+        counters.sent(5);
+        thread::sleep(Duration::from_millis(100));
+        collector.sample(3);
+
+        let stats = collector.stats().clone();
+        assert!(stats.messages_in > 0.0 && stats.messages_in <= 20.0);
+        assert_eq!(stats.messages_in, 2.0 * stats.messages_out);
+        assert_eq!(stats.bytes_in, 8.0 * stats.bytes_out);
+        assert_eq!(stats.queued, 3);
+
+        collector.sample(0);
+        assert_eq!(collector.stats().messages_in, 0.0);
+        assert_eq!(collector.stats().bytes_out, 0.0);
+    }
+
+    #[test]
+    fn times_only_the_latest_ping() {
+        let mut collector = StatsCollector::new(Arc::new(Counters::default()));
+        let first = collector.ping();
+        let second = collector.ping();
+
+        collector.pong(first);
+        assert_eq!(collector.stats().rtt, None);
+        collector.pong(second);
+        let rtt = collector.stats().rtt;
+        assert!(rtt.is_some());
+
+        // Answered already
+        collector.pong(second);
+        assert_eq!(collector.stats().rtt, rtt);
+    }
+
+    #[test]
+    fn keeps_count_of_reconnects_and_the_last_error() {
+        let mut collector = StatsCollector::new(Arc::new(Counters::default()));
+        collector.error("Connection reset".to_owned());
+        collector.reconnected();
+        collector.error("Broken pipe".to_owned());
+        collector.reconnected();
+
+        assert_eq!(collector.stats().reconnects, 2);
+        assert_eq!(collector.stats().last_error, Some("Broken pipe".to_owned()));
+    }
+}
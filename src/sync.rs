@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{self, Value};
+
+use interpolation;
+use observer::{ServerEvent, ServerObserver};
+use protocol::{ClientFrame, ServerFrame};
+use server::{
+    self, ClientEvent, ClientId, Event, Runtime, ServerHandle, ServerOptions, StreamSenders,
+};
+
+/// Trait implemented by a struct to define a server that owns a piece of shared state, which
+/// syncterm keeps synchronized on every client.
+///
+/// After each input or tick, only the changes made to the state are sent to clients, with a full
+/// snapshot every so often, to every newly connected client, and to any client that missed a
+/// change. Clients of a
+/// `SyncedServer<M, S>` implement `ShellClient<M, S>`, and receive the reconstructed state in
+/// `ShellClient::on_state`.
+pub trait SyncedServer<M, S>
+where
+    M: DeserializeOwned + Send + 'static,
+    S: Serialize,
+{
+    /// The local address to which the server will bind.
+    fn local_address(&self) -> String;
+
+    /// The state when the server starts.
+    fn initial_state(&self) -> S;
+
+    /// Process input received from a single client by updating the shared state.
+    ///
+    /// This function will be synchronously called on inputs in the order that they are received
+    /// from clients. Clients that predict the effect of their own inputs (see
+    /// `ShellClient::predict`) should share its logic with them.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # struct Board { moves: Vec<String> }
+    /// # struct App();
+    /// # impl App {
+    /// fn process_input(&self, board: &mut Board, input: String) {
+    ///     board.moves.push(input);
+    /// }
+    /// # }
+    /// ```
+    fn process_input(&self, state: &mut S, client_message: M);
+
+    /// How often to call `tick`, if at all. Defaults to `None`.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Advances the shared state by `dt`, the time since the last tick.
+    fn tick(&self, _state: &mut S, _dt: Duration) {}
+
+    /// Send a full snapshot of the state, instead of only its changes, once every this many
+    /// updates, so that clients heal from any drift. Defaults to 100.
+    fn snapshot_every(&self) -> u32 {
+        100
+    }
+
+    /// Returns what to report the server's events to and whether to collect statistics about it,
+    /// as `ShellServer::options` does.
+    fn options(&self) -> ServerOptions {
+        ServerOptions::new()
+    }
+}
+
+/// The "main" function for SyncedServers.
+///
+/// Binds a listener to the SyncedServer's local address, handles client connections, pipes client
+/// inputs to the server's `process_input` method, and relays the resulting state changes to all
+/// active client connections.
+///
+/// Returns once every client has gone and the server is shut down. Errors if the listener, or the
+/// server's metrics or admin socket, fail to bind.
+pub fn spawn_synced_and_listen<M, S, T>(server: T) -> Result<(), String>
+where
+    M: DeserializeOwned + Send + 'static,
+    S: Serialize,
+    T: SyncedServer<M, S>,
+{
+    let runtime = server::start(&server.local_address(), server.options())?;
+    server::run(runtime, |runtime| run_synced(&server, runtime));
+    Ok(())
+}
+
+/// Like `spawn_synced_and_listen`, but runs the server in the background, returning a handle to
+/// it once it's listening.
+///
+/// Errors if the listener, or the server's metrics or admin socket, fail to bind.
+pub fn spawn_synced<M, S, T>(server: T) -> Result<ServerHandle, String>
+where
+    M: DeserializeOwned + Send + 'static,
+    S: Serialize,
+    T: SyncedServer<M, S> + Send + 'static,
+{
+    let runtime = server::start(&server.local_address(), server.options())?;
+    Ok(ServerHandle::spawn(runtime, move |runtime| {
+        run_synced(&server, runtime)
+    }))
+}
+
+/// The server's main loop, which runs until every client has gone and the server is shut down.
+fn run_synced<M, S, T>(server: &T, runtime: Runtime<M, ()>)
+where
+    M: DeserializeOwned + Send + 'static,
+    S: Serialize,
+    T: SyncedServer<M, S>,
+{
+    let Runtime {
+        events: stm_shl_rx,
+        senders: shl_stm_sxs,
+        observer,
+        ..
+    } = runtime;
+
+    let mut synced = SyncedState::new(server.initial_state(), server.snapshot_every());
+    // The last input processed from each client, so they can tell which of their own inputs the
+    // state they receive includes
+    let mut acked = HashMap::new();
+    let tick_interval = server.tick_interval();
+    let mut last_tick = Instant::now();
+
+    loop {
+        let event = match tick_interval {
+            None => stm_shl_rx
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+            Some(interval) => stm_shl_rx
+                .recv_timeout((last_tick + interval).saturating_duration_since(Instant::now())),
+        };
+
+        match event {
+            Ok(Event::Client(client, ClientEvent::Connected))
+            | Ok(Event::Client(client, ClientEvent::Resync)) => {
+                let (version, update) = synced.snapshot();
+                if let Some(shl_stm_sx) = shl_stm_sxs.lock().unwrap().get(&client) {
+                    shl_stm_sx.send(ServerFrame::State {
+                        version,
+                        update,
+                        ack: acked.get(&client).cloned(),
+                        time: interpolation::now_ms(),
+                    });
+                }
+                continue;
+            }
+            // Dropping the answering sender refuses the announcement
+            Ok(Event::Announce(..)) => continue,
+            Ok(Event::Client(client, ClientEvent::Frame(ClientFrame::Request(id, _)))) => {
+                observer.observe(&ServerEvent::Ignored {
+                    client,
+                    reason: "requests aren't supported when synced".to_owned(),
+                });
+                server::send_to(client, ServerFrame::Refused(id), &shl_stm_sxs);
+                continue;
+            }
+            Ok(Event::Client(client, ClientEvent::Frame(ClientFrame::Input(seq, input))))
+            | Ok(Event::Client(
+                client,
+                ClientEvent::Frame(ClientFrame::TurnInput(seq, _, input)),
+            )) => {
+                let started = Instant::now();
+                server.process_input(&mut synced.state, input);
+                observer.observe(&ServerEvent::InputProcessed {
+                    client,
+                    elapsed: started.elapsed(),
+                });
+                acked.insert(client, seq);
+
+                // An input that changed nothing still needs acknowledging
+                if let Some((version, update)) = synced.update() {
+                    relay_state(version, update, &acked, &shl_stm_sxs, &*observer);
+                } else {
+                    server::send_to(client, ServerFrame::Ack(seq), &shl_stm_sxs);
+                }
+                continue;
+            }
+            Ok(Event::Client(client, ClientEvent::Disconnected)) => {
+                acked.remove(&client);
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                server.tick(&mut synced.state, now - last_tick);
+                last_tick = now;
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if let Some((version, update)) = synced.update() {
+            relay_state(version, update, &acked, &shl_stm_sxs, &*observer);
+        }
+    }
+}
+
+/// Sends a state update to every connected client, along with the last of its inputs processed.
+fn relay_state(
+    version: u64,
+    update: StateUpdate,
+    acked: &HashMap<ClientId, u64>,
+    shl_stm_sxs: &StreamSenders<()>,
+    observer: &dyn ServerObserver,
+) {
+    let time = interpolation::now_ms();
+
+    let mut guard = shl_stm_sxs.lock().expect("Poisoned map of outgoing sxs");
+    guard.retain(|client, shl_stm_sx| {
+        shl_stm_sx.send(ServerFrame::State {
+            version,
+            update: update.clone(),
+            ack: acked.get(client).cloned(),
+            time,
+        })
+    });
+    observer.observe(&ServerEvent::Broadcast {
+        clients: guard.len(),
+    });
+}
+
+/// A change to the synchronized state, as sent to clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum StateUpdate {
+    /// The whole state.
+    Snapshot(Value),
+    /// The changes since the previous version, as a JSON Patch (RFC 6902).
+    Delta(Vec<PatchOp>),
+}
+
+/// A JSON Patch operation. Only the operations produced by `diff` are supported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// The server's copy of the synchronized state, tracking what clients have been sent.
+pub(crate) struct SyncedState<S> {
+    pub state: S,
+    sent: Value,
+    version: u64,
+    snapshot_every: u32,
+    since_snapshot: u32,
+}
+
+impl<S: Serialize> SyncedState<S> {
+    pub fn new(state: S, snapshot_every: u32) -> Self {
+        Self {
+            sent: serde_json::to_value(&state).expect("Synced state failed to serialize"),
+            state,
+            version: 0,
+            snapshot_every: snapshot_every.max(1),
+            since_snapshot: 0,
+        }
+    }
+
+    /// The current version, in full.
+    pub fn snapshot(&self) -> (u64, StateUpdate) {
+        (self.version, StateUpdate::Snapshot(self.sent.clone()))
+    }
+
+    /// Bumps the version if the state changed since it was last sent, returning the update that
+    /// brings clients up to date.
+    pub fn update(&mut self) -> Option<(u64, StateUpdate)> {
+        let current = serde_json::to_value(&self.state).expect("Synced state failed to serialize");
+
+        let mut ops = Vec::new();
+        diff("", &self.sent, &current, &mut ops);
+        if ops.is_empty() {
+            return None;
+        }
+
+        self.version += 1;
+        self.since_snapshot += 1;
+        let update = if self.since_snapshot >= self.snapshot_every {
+            self.since_snapshot = 0;
+            StateUpdate::Snapshot(current.clone())
+        } else {
+            StateUpdate::Delta(ops)
+        };
+
+        self.sent = current;
+        Some((self.version, update))
+    }
+}
+
+/// A client's copy of the synchronized state, rebuilt from the updates it receives.
+#[derive(Default)]
+pub(crate) struct StateReplica {
+    version: Option<u64>,
+    value: Value,
+    // Whether a delta was dropped since the last snapshot, and whether a snapshot's been asked for
+    missed: bool,
+    asked: bool,
+}
+
+impl StateReplica {
+    /// Whether to ask the server for a snapshot, having dropped a delta since the last one. Only
+    /// returns true once until the snapshot arrives.
+    pub fn should_resync(&mut self) -> bool {
+        let ask = self.missed && !self.asked;
+        self.asked |= ask;
+        ask
+    }
+
+    /// The current state, if it is known.
+    pub fn current(&self) -> Option<&Value> {
+        self.version.map(|_| &self.value)
+    }
+
+    /// Applies an update, returning the new state if it is known.
+    ///
+    /// Deltas that don't follow on from the current version are dropped, leaving the replica to
+    /// wait for a snapshot, which `should_resync` says to ask for.
+    pub fn apply(&mut self, version: u64, update: StateUpdate) -> Option<&Value> {
+        match update {
+            StateUpdate::Snapshot(value) => {
+                self.value = value;
+                self.version = Some(version);
+                self.missed = false;
+                self.asked = false;
+            }
+            StateUpdate::Delta(ops) => {
+                if self.version.map(|v| v + 1) != Some(version) {
+                    self.missed = true;
+                    return None;
+                }
+
+                if ops
+                    .into_iter()
+                    .all(|op| patch(&mut self.value, op).is_some())
+                {
+                    self.version = Some(version);
+                } else {
+                    self.version = None;
+                    self.missed = true;
+                    return None;
+                }
+            }
+        }
+
+        Some(&self.value)
+    }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Appends the operations that turn `from` into `to` to `ops`.
+fn diff(path: &str, from: &Value, to: &Value, ops: &mut Vec<PatchOp>) {
+    match (from, to) {
+        (Value::Object(from), Value::Object(to)) => {
+            for (key, from_value) in from {
+                let path = child_path(path, key);
+                match to.get(key) {
+                    Some(to_value) => diff(&path, from_value, to_value, ops),
+                    None => ops.push(PatchOp::Remove { path }),
+                }
+            }
+            for (key, to_value) in to {
+                if !from.contains_key(key) {
+                    ops.push(PatchOp::Add {
+                        path: child_path(path, key),
+                        value: to_value.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(from), Value::Array(to)) => {
+            for (i, (from_value, to_value)) in from.iter().zip(to).enumerate() {
+                diff(&child_path(path, &i.to_string()), from_value, to_value, ops);
+            }
+            // Remove from the back, so that earlier indices stay valid
+            for i in (to.len()..from.len()).rev() {
+                ops.push(PatchOp::Remove {
+                    path: child_path(path, &i.to_string()),
+                });
+            }
+            for (i, to_value) in to.iter().enumerate().skip(from.len()) {
+                ops.push(PatchOp::Add {
+                    path: child_path(path, &i.to_string()),
+                    value: to_value.clone(),
+                });
+            }
+        }
+        (from, to) => {
+            if from != to {
+                ops.push(PatchOp::Replace {
+                    path: path.to_owned(),
+                    value: to.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Applies a single operation to `doc`, returning `None` if its path doesn't exist.
+fn patch(doc: &mut Value, op: PatchOp) -> Option<()> {
+    let (path, value) = match op {
+        PatchOp::Replace { path, value } => {
+            *doc.pointer_mut(&path)? = value;
+            return Some(());
+        }
+        PatchOp::Add { path, value } => (path, Some(value)),
+        PatchOp::Remove { path } => (path, None),
+    };
+
+    let (parent, key) = path.rsplit_once('/')?;
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match (doc.pointer_mut(parent)?, value) {
+        (Value::Object(map), Some(value)) => {
+            map.insert(key, value);
+        }
+        (Value::Object(map), None) => {
+            map.remove(&key)?;
+        }
+        (Value::Array(array), Some(value)) => {
+            let i = key.parse().ok().filter(|&i| i <= array.len())?;
+            array.insert(i, value);
+        }
+        (Value::Array(array), None) => {
+            let i = key.parse().ok().filter(|&i| i < array.len())?;
+            array.remove(i);
+        }
+        _ => return None,
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use admin::Control;
+    use serde_json::json;
+    use server::ClientSender;
+
+    fn delta(from: &Value, to: &Value) -> Vec<PatchOp> {
+        let mut ops = Vec::new();
+        diff("", from, to, &mut ops);
+        ops
+    }
+
+    fn patched(from: &Value, ops: Vec<PatchOp>) -> Option<Value> {
+        let mut doc = from.clone();
+        for op in ops {
+            patch(&mut doc, op)?;
+        }
+        Some(doc)
+    }
+
+    #[test]
+    fn diffs_only_what_changed() {
+        let from = json!({"name": "board", "moves": ["e4", "e5"], "turn": 2});
+        let to = json!({"name": "board", "moves": ["e4", "e5", "Nf3"], "turn": 3});
+
+        assert_eq!(
+            delta(&from, &to),
+            vec![
+                PatchOp::Add {
+                    path: "/moves/2".to_owned(),
+                    value: json!("Nf3"),
+                },
+                PatchOp::Replace {
+                    path: "/turn".to_owned(),
+                    value: json!(3),
+                },
+            ]
+        );
+        assert!(delta(&to, &to).is_empty());
+    }
+
+    #[test]
+    fn patches_turn_one_state_into_another() {
+        let cases = vec![
+            (
+                json!({"a": 1, "b": {"c": [1, 2, 3]}}),
+                json!({"b": {"c": [1], "d": null}, "e": true}),
+            ),
+            (
+                json!([1, [2, 3], {"x": 4}]),
+                json!([5, [2], {"x": 4, "y": []}, 6, 7]),
+            ),
+            (json!({"a/b": 1, "c~d": 2}), json!({"a/b": 3, "e~/f": 4})),
+            (json!({"a": [1, 2]}), json!("replaced")),
+        ];
+
+        for (from, to) in cases {
+            assert_eq!(patched(&from, delta(&from, &to)), Some(to));
+        }
+    }
+
+    #[test]
+    fn fails_to_patch_paths_that_dont_exist() {
+        let doc = json!({"a": [1]});
+
+        assert_eq!(
+            patched(
+                &doc,
+                vec![PatchOp::Remove {
+                    path: "/b".to_owned(),
+                }]
+            ),
+            None
+        );
+        assert_eq!(
+            patched(
+                &doc,
+                vec![PatchOp::Add {
+                    path: "/a/5".to_owned(),
+                    value: json!(2),
+                }]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn sends_deltas_between_snapshots() {
+        let mut synced = SyncedState::new(json!({"count": 0}), 2);
+        assert!(synced.update().is_none());
+
+        synced.state["count"] = json!(1);
+        match synced.update() {
+            Some((1, StateUpdate::Delta(ops))) => assert_eq!(ops.len(), 1),
+            update => panic!("Expected a delta, got {:?}", update),
+        }
+
+        synced.state["count"] = json!(2);
+        match synced.update() {
+            Some((2, StateUpdate::Snapshot(value))) => assert_eq!(value, json!({"count": 2})),
+            update => panic!("Expected a snapshot, got {:?}", update),
+        }
+    }
+
+    #[test]
+    fn replica_follows_the_server() {
+        let mut synced = SyncedState::new(json!({"moves": []}), 10);
+        let mut replica = StateReplica::default();
+        let (version, snapshot) = synced.snapshot();
+        assert_eq!(
+            replica.apply(version, snapshot),
+            Some(&json!({"moves": []}))
+        );
+
+        let mut latest = None;
+        for m in &["e4", "e5"] {
+            synced.state["moves"].as_array_mut().unwrap().push(json!(m));
+            let (version, update) = synced.update().unwrap();
+            latest = replica.apply(version, update).cloned();
+        }
+        assert_eq!(latest, Some(json!({"moves": ["e4", "e5"]})));
+    }
+
+    #[test]
+    fn replica_waits_for_a_snapshot_after_missing_a_delta() {
+        let mut replica = StateReplica::default();
+        let delta = |n| {
+            StateUpdate::Delta(vec![PatchOp::Replace {
+                path: "/n".to_owned(),
+                value: json!(n),
+            }])
+        };
+
+        // Nothing to apply a delta to before the first snapshot
+        assert_eq!(replica.apply(1, delta(1)), None);
+
+        replica.apply(1, StateUpdate::Snapshot(json!({"n": 1})));
+        assert_eq!(replica.apply(3, delta(3)), None);
+        // Still at the snapshot, which the missing delta applies to
+        assert_eq!(replica.apply(2, delta(2)), Some(&json!({"n": 2})));
+    }
+
+    #[test]
+    fn replica_forgets_a_state_it_fails_to_patch() {
+        let mut replica = StateReplica::default();
+        replica.apply(0, StateUpdate::Snapshot(json!({"n": 0})));
+
+        let bad = StateUpdate::Delta(vec![PatchOp::Remove {
+            path: "/missing".to_owned(),
+        }]);
+        assert_eq!(replica.apply(1, bad), None);
+
+        // Nothing to apply the next delta to, until a snapshot arrives
+        let next = StateUpdate::Delta(vec![PatchOp::Add {
+            path: "/m".to_owned(),
+            value: json!(2),
+        }]);
+        assert_eq!(replica.apply(2, next), None);
+    }
+
+    #[test]
+    fn replica_asks_for_a_snapshot_once_after_missing_a_delta() {
+        let mut replica = StateReplica::default();
+        let delta = |n| {
+            StateUpdate::Delta(vec![PatchOp::Replace {
+                path: "/n".to_owned(),
+                value: json!(n),
+            }])
+        };
+        replica.apply(0, StateUpdate::Snapshot(json!({"n": 0})));
+        assert!(!replica.should_resync());
+
+        assert_eq!(replica.apply(2, delta(2)), None);
+        assert!(replica.should_resync());
+        assert_eq!(replica.apply(3, delta(3)), None);
+        assert!(!replica.should_resync());
+
+        // Recovers from the snapshot, and asks again after the next gap
+        assert_eq!(
+            replica.apply(3, StateUpdate::Snapshot(json!({"n": 3}))),
+            Some(&json!({"n": 3}))
+        );
+        assert_eq!(replica.apply(4, delta(4)), Some(&json!({"n": 4})));
+        assert!(!replica.should_resync());
+        assert_eq!(replica.apply(6, delta(6)), None);
+        assert!(replica.should_resync());
+    }
+
+    struct Counter;
+
+    impl SyncedServer<u32, u32> for Counter {
+        fn local_address(&self) -> String {
+            "127.0.0.1:0".to_owned()
+        }
+
+        fn initial_state(&self) -> u32 {
+            0
+        }
+
+        fn process_input(&self, count: &mut u32, n: u32) {
+            *count += n;
+        }
+    }
+
+    #[test]
+    fn sends_a_snapshot_to_a_client_that_asks_for_one() {
+        let client = ClientId(1);
+        let observer: Arc<dyn ServerObserver> = Arc::new(|_: &ServerEvent| {});
+        let (sx, rx) = channel();
+        let mut senders = HashMap::new();
+        let queued = Arc::new(AtomicUsize::new(0));
+        senders.insert(
+            client,
+            ClientSender::new(client, sx, queued, observer.clone()),
+        );
+
+        let (events_sx, events) = channel();
+        let client_events = vec![
+            ClientEvent::Connected,
+            ClientEvent::Frame(ClientFrame::Input(1, 5)),
+            ClientEvent::Resync,
+        ];
+        for event in client_events {
+            events_sx.send(Event::Client(client, event)).unwrap();
+        }
+        drop(events_sx);
+
+        run_synced(
+            &Counter,
+            Runtime {
+                events,
+                senders: Arc::new(Mutex::new(senders)),
+                observer,
+                recorder: None,
+                control: Arc::new(Control::default()),
+            },
+        );
+
+        let states: Vec<_> = rx
+            .try_iter()
+            .filter_map(|frame| match frame {
+                ServerFrame::State {
+                    version,
+                    update: StateUpdate::Snapshot(value),
+                    ack,
+                    ..
+                } => Some(format!("snapshot {} of {} after {:?}", version, value, ack)),
+                ServerFrame::State { version, ack, .. } => {
+                    Some(format!("delta {} after {:?}", version, ack))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                "snapshot 0 of 0 after None",
+                "delta 1 after Some(1)",
+                "snapshot 1 of 5 after Some(1)",
+            ]
+        );
+    }
+}
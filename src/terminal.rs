@@ -0,0 +1,195 @@
+use std::io::{self, Write};
+use std::panic;
+use std::sync::{Mutex, Once};
+
+use termion::cursor;
+use termion::input::MouseTerminal;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+
+/// Returned by `ShellClient::terminal_options` to choose which parts of the terminal's lifecycle
+/// `connect` manages.
+///
+/// Whatever is set up on entry is restored when the client exits, whether it exits normally, with
+/// an error, or by panicking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TerminalOptions {
+    /// Puts the terminal in raw mode, so that keys arrive as they are pressed, unechoed.
+    pub raw_mode: bool,
+    /// Switches to the alternate screen, leaving the shell's scrollback untouched.
+    pub alternate_screen: bool,
+    /// Hides the cursor.
+    pub hide_cursor: bool,
+    /// Enables mouse reporting. Also set by `ShellClient::capture_mouse`.
+    pub mouse: bool,
+    /// Asks the terminal to mark pasted text, so that `LineEditor::handle_event` can tell it apart
+    /// from typing.
+    pub bracketed_paste: bool,
+}
+
+impl TerminalOptions {
+    /// Manages raw mode, the alternate screen and the cursor, as needed by full-screen UIs.
+    pub fn managed() -> Self {
+        Self {
+            raw_mode: true,
+            alternate_screen: true,
+            hide_cursor: true,
+            mouse: false,
+            bracketed_paste: false,
+        }
+    }
+}
+
+const ENABLE_BRACKETED_PASTE: &str = "\x1b[?2004h";
+const DISABLE_BRACKETED_PASTE: &str = "\x1b[?2004l";
+
+// The layers of terminal setup currently in effect, undone by dropping them. Global, so that the
+// panic hook can get at it.
+static ACTIVE_SESSION: Mutex<Option<(Box<dyn Write + Send>, TerminalOptions)>> = Mutex::new(None);
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+/// A terminal set up according to some `TerminalOptions`, restored when dropped.
+pub(crate) struct TerminalSession;
+
+impl TerminalSession {
+    pub fn enter(options: TerminalOptions) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = if options.raw_mode {
+            Box::new(io::stdout().into_raw_mode()?)
+        } else {
+            Box::new(io::stdout())
+        };
+        Self::enter_on(output, options)
+    }
+
+    /// Sets up the terminal that `output` writes to, which is already in raw mode if the options
+    /// ask for it.
+    fn enter_on(mut output: Box<dyn Write + Send>, options: TerminalOptions) -> io::Result<Self> {
+        if options.alternate_screen {
+            output = Box::new(AlternateScreen::from(output));
+        }
+        if options.mouse {
+            output = Box::new(MouseTerminal::from(output));
+        }
+        if options.hide_cursor {
+            write!(output, "{}", cursor::Hide)?;
+        }
+        if options.bracketed_paste {
+            write!(output, "{}", ENABLE_BRACKETED_PASTE)?;
+        }
+        output.flush()?;
+
+        INSTALL_PANIC_HOOK.call_once(|| {
+            // Restore the terminal before the panic message is printed, so that it's readable
+            let previous_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                restore();
+                previous_hook(info);
+            }));
+        });
+        *ACTIVE_SESSION.lock().unwrap_or_else(|e| e.into_inner()) = Some((output, options));
+
+        Ok(TerminalSession)
+    }
+}
+
+impl Drop for TerminalSession {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Undoes the active session's terminal setup, if there is one.
+fn restore() {
+    let session = ACTIVE_SESSION
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+
+    if let Some((mut output, options)) = session {
+        if options.hide_cursor {
+            let _ = write!(output, "{}", cursor::Show);
+        }
+        if options.bracketed_paste {
+            let _ = write!(output, "{}", DISABLE_BRACKETED_PASTE);
+        }
+        let _ = output.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::sync::Arc;
+    use std::thread;
+
+    use termion::screen::ToMainScreen;
+
+    use super::*;
+
+    // Only one session is active at a time, so the tests take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    /// Collects what's written to the terminal.
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl Written {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn options() -> TerminalOptions {
+        TerminalOptions {
+            alternate_screen: true,
+            hide_cursor: true,
+            mouse: true,
+            ..TerminalOptions::default()
+        }
+    }
+
+    #[test]
+    fn restores_the_terminal_when_dropped() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let written = Written::default();
+
+        let session = TerminalSession::enter_on(Box::new(written.clone()), options()).unwrap();
+        assert!(written.text().contains(&cursor::Hide.to_string()));
+        assert!(!written.text().contains(&cursor::Show.to_string()));
+
+        drop(session);
+        let text = written.text();
+        assert!(text.contains(&cursor::Show.to_string()));
+        assert!(text.ends_with(&ToMainScreen.to_string()));
+    }
+
+    #[test]
+    fn restores_the_terminal_on_panic_before_unwinding() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let written = Written::default();
+
+        let output = written.clone();
+        let panicked = thread::spawn(move || {
+            // Leaves restoring the terminal to the panic hook alone
+            mem::forget(TerminalSession::enter_on(Box::new(output), options()).unwrap());
+            panic!("The client panicked");
+        })
+        .join();
+
+        assert!(panicked.is_err());
+        let text = written.text();
+        assert!(text.contains(&cursor::Show.to_string()));
+        assert!(text.ends_with(&ToMainScreen.to_string()));
+    }
+}
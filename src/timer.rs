@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chan;
+
+/// Delivers scheduled items on a `chan::Receiver` once their deadline has passed, so that they
+/// can join a `chan_select!` alongside other event sources.
+pub(crate) struct Timer<T> {
+    schedule_sx: Sender<(Instant, T)>,
+}
+
+impl<T: Send + 'static> Timer<T> {
+    /// Spawns the timer thread. The thread exits once the `Timer`, and every clone of it, is
+    /// dropped.
+    pub fn new() -> (Self, chan::Receiver<T>) {
+        let (schedule_sx, schedule_rx) = channel::<(Instant, T)>();
+        let (fired_tx, fired_rx) = chan::async();
+
+        thread::spawn(move || {
+            // Keyed by a tie-breaking counter too, so equal deadlines don't overwrite each other
+            let mut scheduled = BTreeMap::<(Instant, u64), T>::new();
+            let mut counter = 0u64;
+
+            loop {
+                let next = match scheduled.keys().next() {
+                    None => schedule_rx
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                    Some(&(deadline, _)) => {
+                        schedule_rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                };
+
+                match next {
+                    Ok((deadline, item)) => {
+                        scheduled.insert((deadline, counter), item);
+                        counter += 1;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                let now = Instant::now();
+                while let Some(&key) = scheduled.keys().next() {
+                    if key.0 > now {
+                        break;
+                    }
+                    fired_tx.send(scheduled.remove(&key).unwrap());
+                }
+            }
+        });
+
+        (Self { schedule_sx }, fired_rx)
+    }
+
+    /// Schedules `item` to be delivered after `delay`. A delay too long to represent, such as
+    /// `Duration::MAX`, never fires.
+    pub fn schedule(&self, delay: Duration, item: T) {
+        let deadline = match Instant::now().checked_add(delay) {
+            Some(deadline) => deadline,
+            None => return,
+        };
+
+        // The timer thread only exits once this sender is dropped
+        let _ = self.schedule_sx.send((deadline, item));
+    }
+}
+
+// Derived, this would require `T: Clone`
+impl<T> Clone for Timer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            schedule_sx: self.schedule_sx.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_items_in_deadline_order() {
+        let (timer, fired) = Timer::new();
+        timer.schedule(Duration::from_millis(60), "third");
+        timer.schedule(Duration::from_millis(20), "first");
+        timer.schedule(Duration::from_millis(40), "second");
+        timer.schedule(Duration::from_millis(0), "now");
+
+        let order: Vec<_> = (0..4).map(|_| fired.recv().unwrap()).collect();
+        assert_eq!(order, vec!["now", "first", "second", "third"]);
+    }
+
+    #[test]
+    fn waits_out_the_delay() {
+        let (timer, fired) = Timer::new();
+        let scheduled = Instant::now();
+        timer.schedule(Duration::from_millis(30), ());
+
+        fired.recv();
+        assert!(scheduled.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn never_fires_delays_too_long_to_represent() {
+        let (timer, fired) = Timer::new();
+        timer.schedule(Duration::MAX, "never");
+        timer.schedule(Duration::from_millis(10), "soon");
+
+        assert_eq!(fired.recv(), Some("soon"));
+        drop(timer);
+        assert_eq!(fired.recv(), None);
+    }
+
+    #[test]
+    fn drops_whats_scheduled_once_every_timer_is_dropped() {
+        let (timer, fired) = Timer::new();
+        let clone = timer.clone();
+        timer.schedule(Duration::from_millis(20), ());
+        drop(timer);
+        clone.schedule(Duration::from_millis(20), ());
+        drop(clone);
+
+        assert_eq!(fired.recv(), None);
+    }
+}
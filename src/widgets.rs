@@ -0,0 +1,396 @@
+//! Ready-made `tui` components for ShellClients, enabled by the `widgets` feature.
+//!
+//! Each component holds its own state, updated from the client's callbacks, and implements
+//! `tui::widgets::Widget` so it can be rendered in the client's `draw`.
+//!
+//! # Examples
+//! ```
+//! # extern crate syncterm;
+//! # extern crate tui;
+//! # use syncterm::client::Key;
+//! # use syncterm::widgets::{InputBox, MessageLog};
+//! # use tui::Terminal;
+//! # use tui::backend::MouseBackend;
+//! # use tui::layout::Rect;
+//! # struct App { log: MessageLog, input: InputBox, size: Rect, terminal: Terminal<MouseBackend> }
+//! # impl App {
+//! fn on_key(&mut self, key: Key) {
+//!     if let Some(line) = self.input.handle_key(key) {
+//!         self.log.push("me", &line);
+//!     }
+//! }
+//!
+//! fn draw(&mut self) {
+//!     let layout = syncterm::widgets::chat_layout(&self.size);
+//!     self.terminal.render(&mut self.log, &layout.log);
+//!     self.terminal.render(&mut self.input, &layout.input);
+//!     self.terminal.draw().unwrap();
+//! }
+//! # }
+//! # fn main() {}
+//! ```
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use tui::buffer::Buffer;
+use tui::layout::{self, Direction, Rect, Size};
+use tui::style::{Color, Style};
+use tui::widgets::{Block, Borders, Paragraph, Widget};
+
+use client::Key;
+use editor::{Edit, LineEditor};
+
+/// How many columns wide the roster pane is in a `ChatLayout`.
+const ROSTER_WIDTH: u16 = 20;
+
+/// Where each component goes in a typical chat client: the input box along the top, the message
+/// log and a roster pane beside it in the middle, and the status bar along the bottom.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChatLayout {
+    pub input: Rect,
+    pub log: Rect,
+    pub roster: Rect,
+    pub status: Rect,
+}
+
+/// Splits `area` up into a `ChatLayout`.
+pub fn chat_layout(area: &Rect) -> ChatLayout {
+    let rows = layout::split(
+        area,
+        &Direction::Vertical,
+        0,
+        &[Size::Fixed(3), Size::Min(1), Size::Fixed(1)],
+    );
+    let columns = layout::split(
+        &rows[1],
+        &Direction::Horizontal,
+        0,
+        &[Size::Min(1), Size::Fixed(ROSTER_WIDTH)],
+    );
+
+    ChatLayout {
+        input: rows[0],
+        log: columns[0],
+        roster: columns[1],
+        status: rows[2],
+    }
+}
+
+/// A scrollable log of messages, each shown with the time it arrived and who sent it, newest
+/// first.
+pub struct MessageLog {
+    title: String,
+    time_format: String,
+    messages: Vec<(DateTime<Local>, String, String)>,
+    scroll: u16,
+}
+
+impl MessageLog {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            time_format: "%H:%M:%S".to_owned(),
+            messages: Vec::new(),
+            scroll: 0,
+        }
+    }
+
+    /// Sets how the time of each message is shown, as a `chrono` format string. Defaults to
+    /// `"%H:%M:%S"`.
+    pub fn time_format(mut self, time_format: &str) -> Self {
+        self.time_format = time_format.to_owned();
+        self
+    }
+
+    /// Adds a message from `user`, received now.
+    pub fn push(&mut self, user: &str, message: &str) {
+        self.push_at(Local::now(), user, message);
+    }
+
+    /// Adds a message from `user`, received at `time`.
+    pub fn push_at(&mut self, time: DateTime<Local>, user: &str, message: &str) {
+        self.messages
+            .push((time, user.to_owned(), message.to_owned()));
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.scroll = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Scrolls back towards older messages by `lines`.
+    pub fn scroll_down(&mut self, lines: u16) {
+        self.scroll = self.scroll.saturating_add(lines);
+    }
+
+    /// Scrolls forward towards newer messages by `lines`.
+    pub fn scroll_up(&mut self, lines: u16) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+}
+
+impl Widget for MessageLog {
+    fn draw(&mut self, area: &Rect, buf: &mut Buffer) {
+        let text = self
+            .messages
+            .iter()
+            .rev()
+            .map(|(time, user, message)| {
+                format!(
+                    "{}: {}: {}\n",
+                    time.format(&self.time_format),
+                    user,
+                    message
+                )
+            })
+            .collect::<String>();
+
+        Paragraph::default()
+            .block(Block::default().borders(Borders::ALL).title(&self.title))
+            .wrap(true)
+            .raw(true)
+            .scroll(self.scroll)
+            .text(&text)
+            .draw(area, buf);
+    }
+}
+
+/// A single-line text input, edited with a `LineEditor`.
+pub struct InputBox {
+    title: String,
+    editor: LineEditor,
+}
+
+impl InputBox {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            editor: LineEditor::new(),
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_owned();
+    }
+
+    /// The text typed so far.
+    pub fn input(&self) -> &str {
+        self.editor.input()
+    }
+
+    pub fn editor_mut(&mut self) -> &mut LineEditor {
+        &mut self.editor
+    }
+
+    /// Edits the input with a key press. Returns the input, and clears it, when Enter is pressed.
+    pub fn handle_key(&mut self, key: Key) -> Option<String> {
+        match self.editor.handle_key(key) {
+            Edit::Submit(input) => Some(input),
+            Edit::Handled | Edit::Unhandled => None,
+        }
+    }
+}
+
+impl Widget for InputBox {
+    fn draw(&mut self, area: &Rect, buf: &mut Buffer) {
+        Paragraph::default()
+            .style(Style::default().fg(Color::Yellow))
+            .block(Block::default().borders(Borders::ALL).title(&self.title))
+            .raw(true)
+            .text(self.editor.input())
+            .draw(area, buf);
+    }
+}
+
+/// A single line showing whether the client is connected, the latency to the server and any
+/// other text.
+#[derive(Default)]
+pub struct StatusBar {
+    connected: bool,
+    latency: Option<Duration>,
+    text: String,
+}
+
+impl StatusBar {
+    /// Starts out connected, since `client::connect` only runs clients once they are.
+    pub fn new() -> Self {
+        Self {
+            connected: true,
+            ..Self::default()
+        }
+    }
+
+    /// Call from `ShellClient::on_connection`.
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+    }
+
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = Some(latency);
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.to_owned();
+    }
+}
+
+impl Widget for StatusBar {
+    fn draw(&mut self, area: &Rect, buf: &mut Buffer) {
+        let (state, color) = if self.connected {
+            ("connected", Color::Green)
+        } else {
+            ("disconnected", Color::Red)
+        };
+        let mut status = format!(" {}", state);
+        if let Some(latency) = self.latency {
+            status.push_str(&format!(" | {}ms", latency.as_millis()));
+        }
+        if !self.text.is_empty() {
+            status.push_str(&format!(" | {}", self.text));
+        }
+
+        Paragraph::default()
+            .style(Style::default().fg(color))
+            .raw(true)
+            .text(&status)
+            .draw(area, buf);
+    }
+}
+
+/// A pane listing who else is connected.
+pub struct Roster {
+    title: String,
+    names: Vec<String>,
+}
+
+impl Roster {
+    pub fn new(title: &str) -> Self {
+        Self {
+            title: title.to_owned(),
+            names: Vec::new(),
+        }
+    }
+
+    /// Adds `name`, unless it's already listed.
+    pub fn insert(&mut self, name: &str) {
+        if !self.names.iter().any(|n| n == name) {
+            self.names.push(name.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.names.retain(|n| n != name);
+    }
+
+    /// Replaces everyone listed.
+    pub fn set(&mut self, names: Vec<String>) {
+        self.names = names;
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+impl Widget for Roster {
+    fn draw(&mut self, area: &Rect, buf: &mut Buffer) {
+        let text = self.names.join("\n");
+
+        Paragraph::default()
+            .block(Block::default().borders(Borders::ALL).title(&self.title))
+            .raw(true)
+            .text(&text)
+            .draw(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(buf: &Buffer, y: u16) -> String {
+        (0..buf.area().width)
+            .map(|x| buf.get(x, y).symbol.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn lays_a_chat_client_out_over_the_whole_area() {
+        let layout = chat_layout(&Rect::new(0, 0, 80, 24));
+
+        assert_eq!(layout.input, Rect::new(0, 0, 80, 3));
+        assert_eq!(layout.log, Rect::new(0, 3, 60, 20));
+        assert_eq!(layout.roster, Rect::new(60, 3, ROSTER_WIDTH, 20));
+        assert_eq!(layout.status, Rect::new(0, 23, 80, 1));
+    }
+
+    #[test]
+    fn draws_each_component_inside_its_part_of_the_layout() {
+        let area = Rect::new(0, 0, 80, 24);
+        let layout = chat_layout(&area);
+        let mut buf = Buffer::empty(area);
+
+        let mut input = InputBox::new("Chat");
+        for c in "hey".chars() {
+            input.handle_key(Key::Char(c));
+        }
+        let mut log = MessageLog::new("Messages").time_format("[t]");
+        log.push("alice", "first");
+        log.push("bob", "second");
+        let mut roster = Roster::new("Users");
+        roster.insert("alice");
+        roster.insert("bob");
+        roster.insert("alice");
+        let mut status = StatusBar::new();
+        status.set_latency(Duration::from_millis(12));
+
+        input.draw(&layout.input, &mut buf);
+        log.draw(&layout.log, &mut buf);
+        roster.draw(&layout.roster, &mut buf);
+        status.draw(&layout.status, &mut buf);
+
+        assert!(row(&buf, 0).starts_with("┌Chat"), "{}", row(&buf, 0));
+        assert!(row(&buf, 1).starts_with("│hey "), "{}", row(&buf, 1));
+        // Newest message first, left of the roster
+        assert!(
+            row(&buf, 4).starts_with("│[t]: bob: second "),
+            "{}",
+            row(&buf, 4)
+        );
+        assert!(
+            row(&buf, 5).starts_with("│[t]: alice: first "),
+            "{}",
+            row(&buf, 5)
+        );
+        assert!(
+            row(&buf, 3).ends_with("┌Users─────────────┐"),
+            "{}",
+            row(&buf, 3)
+        );
+        assert!(
+            row(&buf, 4).ends_with("│alice             │"),
+            "{}",
+            row(&buf, 4)
+        );
+        assert!(
+            row(&buf, 5).ends_with("│bob               │"),
+            "{}",
+            row(&buf, 5)
+        );
+        assert!(
+            row(&buf, 6).ends_with("│                  │"),
+            "{}",
+            row(&buf, 6)
+        );
+        assert_eq!(row(&buf, 23).trim_end(), " connected | 12ms");
+    }
+}